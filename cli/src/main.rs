@@ -1,13 +1,16 @@
 use clap::{Parser, Subcommand};
-use file_carrier::{error::FileCarrierError, init::initialize_file_carrier, register::register_folder, unregister::unregister_folder};
+use file_carrier::{config::Config, error::FileCarrierError, hierarchy::FileCarrierHierarchy, init::initialize_file_carrier, migration::{migrate, MigrationOutcome}, register::register_folder, strategy::ContactStrategy, unregister::unregister_folder};
 use std::{
-    path::PathBuf, process, time::Duration
+    path::{Path, PathBuf}, process, time::Duration
 };
 use uuid::Uuid;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    /// Configuration file to use instead of /etc/archipel-fc/config.toml and the user configuration
+    #[arg(short, long, global = true)]
+    config: Option<PathBuf>,
     #[command(subcommand)]
     command: Commands,
 }
@@ -21,17 +24,28 @@ enum Commands {
     },
     /// Registers a folder to a node through an AAP agent connection. Duration is in seconds
     Register {
-        #[arg(short, long, default_value = "/run/archipel-core/archipel-core.socket")]
-        socket: PathBuf,
+        /// Socket of Archipel Core, defaults to the configured one
+        #[arg(short, long)]
+        socket: Option<PathBuf>,
         #[arg(default_value = ".")]
         folder: PathBuf,
-        #[arg(short, long, default_value_t = 300)]
-        duration: u64,
+        /// Defaults to the configured contact duration
+        #[arg(short, long)]
+        duration: Option<u64>,
+        /// How reached nodes are announced: last-node, all-nodes or top-<N> most visited nodes, defaults to the configured one
+        #[arg(long)]
+        strategy: Option<ContactStrategy>,
     },
     /// Unregister a folder from a node through an AAP agent
     Unregister {
-        #[arg(short, long, default_value = "/run/archipel-core/archipel-core.socket")]
-        socket: PathBuf,
+        /// Socket of Archipel Core, defaults to the configured one
+        #[arg(short, long)]
+        socket: Option<PathBuf>,
+        #[arg(default_value = ".")]
+        folder: PathBuf,
+    },
+    /// Upgrades the hierarchy of a file carrier to the latest layout version
+    Migrate {
         #[arg(default_value = ".")]
         folder: PathBuf,
     },
@@ -42,17 +56,31 @@ fn main() {
 
     match &cli.command {
         Commands::Init { folder } => {
-            if let Err(e) = initialize_file_carrier(&folder) {
-                eprintln!("Failed to initialize folder: {e}");
-                process::exit(11);
+            match initialize_file_carrier(folder) {
+                Ok(report) if report.already_initialized => {
+                    println!("{:?} is already a file carrier", folder);
+                }
+                Ok(report) => {
+                    println!("File carrier initialized in {}", report.folder.display());
+                }
+                Err(e) => {
+                    eprintln!("Failed to initialize folder: {e}");
+                    process::exit(11);
+                }
             }
         }
         Commands::Register {
             socket,
             folder,
             duration,
+            strategy,
         } => {
-            let mut agent = match ud3tn_aap::Agent::connect_unix(&socket)
+            let config = load_config(cli.config.as_deref());
+            let socket = socket.as_ref().unwrap_or(&config.socket);
+            let duration = duration.unwrap_or(config.cli.contact_duration);
+            let strategy = strategy.unwrap_or(config.strategy);
+
+            let mut agent = match ud3tn_aap::Agent::connect_unix(socket)
                     .inspect_err(|e| eprintln!("Failed to connect to node: {e}"))
                     .ok()
                     .and_then(|a| 
@@ -64,9 +92,28 @@ fn main() {
                 None => process::exit(10)
             };
 
-            match register_folder(&mut agent, folder, Duration::from_secs(*duration)) {
-                Ok(_) => {},
-                Err(FileCarrierError::FirstUser(_)) => { process::exit(1) }
+            match register_folder(&mut agent, folder, Duration::from_secs(duration), strategy) {
+                Ok(report) => {
+                    for warning in report.warnings.iter() {
+                        eprintln!("Warning: {warning}");
+                    }
+
+                    if let MigrationOutcome::Migrated { from, to } = report.migration {
+                        println!("Migrated {} from layout version {} to {}", folder.display(), from, to);
+                    }
+
+                    if report.first_use {
+                        println!("You're the only one using this file-carrier");
+                        println!("Connect to another node to establish a connection");
+                        println!("or manually add node EID's in {}", FileCarrierHierarchy::new(folder).reaches_file().display());
+                        process::exit(1)
+                    }
+
+                    for contact in report.contacts.iter() {
+                        println!("Connected to node {} for {} seconds", contact.node_eid, duration);
+                        println!("Reaches are: {}", contact.reaches_eid.join(";"));
+                    }
+                },
                 Err(FileCarrierError::NotAFileCarrier(folder)) => { 
                     eprintln!("Folder {} is not a file carrier (it does not contains a .bundles folder)", folder.to_string_lossy());
                     process::exit(2)
@@ -78,9 +125,11 @@ fn main() {
             };
         }
         Commands::Unregister { socket, folder } => {
+            let config = load_config(cli.config.as_deref());
+            let socket = socket.as_ref().unwrap_or(&config.socket);
 
             let mut agent = match ud3tn_aap::Agent::connect_unix(
-                &socket
+                socket
             ).inspect_err(|e| eprintln!("Failed to connect to node: {e}"))
             .ok()
             .and_then(|a| 
@@ -95,8 +144,9 @@ fn main() {
             };
 
             match unregister_folder(&mut agent, folder) {
-                Ok(_) => {}
-                Err(FileCarrierError::FirstUser(_)) => { process::exit(1) }
+                Ok(report) => {
+                    println!("Unregistered {}", report.folder.display());
+                }
                 Err(FileCarrierError::NotAFileCarrier(folder)) => { 
                     eprintln!("Folder {} is not a file carrier (it does not contains a .bundles folder)", folder.to_string_lossy());
                     process::exit(2)
@@ -107,5 +157,34 @@ fn main() {
                 }
             }
         }
+        Commands::Migrate { folder } => {
+            match migrate(folder) {
+                Ok(MigrationOutcome::UpToDate(version)) => {
+                    println!("{} is already up to date (layout version {})", folder.display(), version);
+                }
+                Ok(MigrationOutcome::Migrated { from, to }) => {
+                    println!("Migrated {} from layout version {} to {}", folder.display(), from, to);
+                }
+                Err(FileCarrierError::NotAFileCarrier(folder)) => { 
+                    eprintln!("Folder {} is not a file carrier (it does not contains a .bundles folder)", folder.to_string_lossy());
+                    process::exit(2)
+                }
+                Err(e) => {
+                    eprintln!("Failed to migrate folder: {e}");
+                    process::exit(11);
+                }
+            }
+        }
+    }
+}
+
+/// Loads the configuration, exiting if it is invalid
+fn load_config(path: Option<&Path>) -> Config {
+    match Config::load(path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Failed to load configuration: {e}");
+            process::exit(11);
+        }
     }
 }
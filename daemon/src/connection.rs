@@ -0,0 +1,134 @@
+use std::{fs, os::unix::{fs::MetadataExt, net::UnixStream}, path::{Path, PathBuf}, time::{Duration, Instant}};
+
+use file_carrier::error::FileCarrierError;
+use ud3tn_aap::{Agent, RegisteredAgent};
+
+/// Identifier of the agent used to configure the node
+pub const AGENT_ID: &str = "file-carrier/config-daemon";
+
+/// Interval between checks of the core socket while connected
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Delay before the first reconnection attempt, doubled after each failure
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Connection of the config agent to Archipel Core, re-established with backoff when the core restarts
+pub struct CoreConnection {
+    socket: PathBuf,
+    /// Registered agent and inode of the socket it is connected to
+    agent: Option<(RegisteredAgent<UnixStream>, u64)>,
+    retry_delay: Duration,
+    retry_at: Instant
+}
+
+impl CoreConnection {
+    /// Creates a connection to the core listening on `socket`, established by the first [CoreConnection::check]
+    pub fn new(socket: PathBuf) -> Self {
+        Self { socket, agent: None, retry_delay: MIN_RETRY_DELAY, retry_at: Instant::now() }
+    }
+
+    /// Returns the agent if the core is connected
+    pub fn agent(&mut self) -> Option<&mut RegisteredAgent<UnixStream>> {
+        self.agent.as_mut().map(|(agent, _)| agent)
+    }
+
+    /// Drops the agent after a failed exchange, the next [CoreConnection::check] reconnects right away
+    pub fn disconnect(&mut self) {
+        if self.agent.take().is_some() {
+            eprintln!("Disconnected from Archipel Core");
+        }
+        self.retry_at = Instant::now();
+    }
+
+    /// Uses another socket, reconnecting if it changed
+    pub fn set_socket(&mut self, socket: PathBuf) {
+        if socket != self.socket {
+            self.socket = socket;
+            self.disconnect();
+        }
+    }
+
+    /// Reconnects if the core socket was removed or replaced, or if a reconnection attempt is due
+    ///
+    /// Returns `true` if a new agent was registered, the core then knows none of the contacts announced before.
+    pub fn check(&mut self) -> bool {
+        if let Some((_, inode)) = &self.agent {
+            if socket_inode(&self.socket) == Some(*inode) {
+                return false;
+            }
+            eprintln!("Archipel Core socket {} was removed or replaced", self.socket.display());
+            self.disconnect();
+        }
+
+        let now = Instant::now();
+        if now < self.retry_at {
+            return false;
+        }
+
+        match connect(&self.socket) {
+            Err(e) => {
+                eprintln!("Failed to connect to Archipel Core on {}, retrying in {} seconds: {e}", self.socket.display(), self.retry_delay.as_secs());
+                self.retry_at = now + self.retry_delay;
+                self.retry_delay = (self.retry_delay * 2).min(MAX_RETRY_DELAY);
+                false
+            },
+            Ok(connected) => {
+                println!("Connected to Archipel Core on {}", self.socket.display());
+                self.agent = Some(connected);
+                self.retry_delay = MIN_RETRY_DELAY;
+                true
+            }
+        }
+    }
+
+    /// Time until the next [CoreConnection::check] is due
+    pub fn next_check(&self) -> Duration {
+        match self.agent {
+            Some(_) => CHECK_INTERVAL,
+            None => self.retry_at.saturating_duration_since(Instant::now())
+        }
+    }
+}
+
+fn connect(socket: &Path) -> Result<(RegisteredAgent<UnixStream>, u64), FileCarrierError> {
+    let inode = fs::metadata(socket)?.ino();
+    let agent = Agent::connect_unix(socket)?
+        .register(AGENT_ID.to_owned())?;
+    Ok((agent, inode))
+}
+
+fn socket_inode(socket: &Path) -> Option<u64> {
+    fs::metadata(socket).ok().map(|metadata| metadata.ino())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use file_carrier::testing::FakeAapServer;
+
+    use crate::connection::{CoreConnection, MIN_RETRY_DELAY};
+
+    #[test]
+    fn reconnection_backoff() {
+        let mut core = CoreConnection::new(PathBuf::from("/nonexistent/archipel-core.socket"));
+        assert!(!core.check());
+        assert!(core.agent().is_none());
+        assert!(core.next_check() <= MIN_RETRY_DELAY);
+
+        // Not due yet
+        assert!(!core.check());
+        assert!(core.next_check() > Duration::ZERO);
+
+        let server = FakeAapServer::start("dtn://current/").unwrap();
+        core.set_socket(server.socket_path().to_path_buf());
+        assert!(core.check());
+        assert!(core.agent().is_some());
+        assert!(!core.check());
+
+        drop(server);
+        assert!(!core.check());
+        assert!(core.agent().is_none());
+    }
+}
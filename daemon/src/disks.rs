@@ -1,8 +1,10 @@
 use std::{collections::HashMap, ffi::{CString, OsString}, fmt::Debug, future::Future, path::PathBuf, task::Poll};
 use std::pin::Pin;
 
+use file_carrier::config::DeviceIdentity;
+
 use futures::{join, FutureExt, Stream, StreamExt};
-use zbus::{fdo::{InterfacesAddedStream, ObjectManagerProxy}, zvariant::{self, OwnedObjectPath, Str}, Connection};
+use zbus::{fdo::{InterfacesAddedStream, InterfacesRemovedStream, ObjectManagerProxy}, zvariant::{self, OwnedObjectPath, Str}, Connection};
 
 pub struct DiskManager<'a> {
     connection: &'a Connection,
@@ -43,6 +45,12 @@ impl<'a> DiskManager<'a> {
             ungoing_task: None
         })
     }
+
+    pub async fn devices_removed(&self) -> Result<DeviceRemovedStream, zbus::Error> {
+        Ok(DeviceRemovedStream{
+            inner_stream: self.object_manager.receive_interfaces_removed().await?
+        })
+    }
 }
 
 pub struct DeviceAddedStream<'a>{
@@ -103,6 +111,38 @@ impl<'a> Stream for DeviceAddedStream<'a> {
     }
 }
 
+/// Stream of object paths of block devices removed from the system
+pub struct DeviceRemovedStream {
+    inner_stream: InterfacesRemovedStream
+}
+
+impl Stream for DeviceRemovedStream {
+    type Item = OwnedObjectPath;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>
+    ) -> std::task::Poll<Option<Self::Item>> {
+        loop {
+            match self.inner_stream.poll_next_unpin(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Ready(Some(item)) => {
+                    let Some(args) = item.args().ok() else {
+                        continue;
+                    };
+
+                    if !args.interfaces.iter().any(|i| i.as_str() == "org.freedesktop.UDisks2.Block") {
+                        continue;
+                    }
+
+                    return Poll::Ready(Some(args.object_path.clone().into()));
+                }
+            }
+        }
+    }
+}
+
 pub struct BlockDevice<'a> {
     connection: &'a Connection,
     path: OwnedObjectPath,
@@ -144,6 +184,33 @@ impl<'a> BlockDevice<'a> {
     pub async fn id(&self) -> Result<String, zbus::Error> {
         self.proxy.id().await
     }
+
+    /// Filesystem type, such as `vfat` or `ext4`
+    pub async fn fs_type(&self) -> Result<String, zbus::Error> {
+        self.proxy.id_type().await
+    }
+
+    /// Properties of the filesystem and of its drive, used to filter devices
+    pub async fn identity(&self) -> Result<DeviceIdentity, zbus::Error> {
+        let mut identity = DeviceIdentity {
+            id: self.id().await?,
+            uuid: self.proxy.id_uuid().await?,
+            label: self.proxy.id_label().await?,
+            ..DeviceIdentity::default()
+        };
+
+        if let Some(drive) = self.drive().await? {
+            identity.serial = drive.proxy.serial().await?;
+            identity.vendor = drive.proxy.vendor().await?;
+            identity.model = drive.proxy.model().await?;
+        }
+
+        Ok(identity)
+    }
+
+    pub fn path(&self) -> &OwnedObjectPath {
+        &self.path
+    }
 }
 
 pub struct Drive<'a> {
@@ -175,6 +242,15 @@ impl<'a> Drive<'a> {
         };
         Ok(cached_removable)
     }
+
+    pub async fn can_power_off(&self) -> Result<bool, zbus::Error> {
+        self.proxy.can_power_off().await
+    }
+
+    /// Powers the drive off, its filesystems must be unmounted first
+    pub async fn power_off(&self) -> Result<(), zbus::Error> {
+        self.proxy.power_off(&HashMap::new()).await
+    }
 }
 
 pub struct MountableDevice<'a>(BlockDevice<'a>, udisks2::FilesystemProxy<'a>);
@@ -216,17 +292,17 @@ impl<'a> MountableDevice<'a> {
         Ok(paths)
     }
 
-    pub async fn mount(&self) -> Result<PathBuf, zbus::Error> {
-        let mountpath = self.1.mount(&HashMap::new()).await?;
-        Ok(PathBuf::from(mountpath))
-    }
-
-    pub async fn mount_as_user(&self, username: &str) -> Result<PathBuf, zbus::Error> {
-
-        let mut options = HashMap::new();
-        options.insert("as-user".to_owned(), zvariant::Value::Str(Str::from(username)));
+    /// Mounts the filesystem, for `as_user` if provided, with additional filesystem `options`
+    pub async fn mount(&self, as_user: Option<&str>, options: &[String]) -> Result<PathBuf, zbus::Error> {
+        let mut mount_options = HashMap::new();
+        if let Some(username) = as_user {
+            mount_options.insert("as-user".to_owned(), zvariant::Value::Str(Str::from(username)));
+        }
+        if !options.is_empty() {
+            mount_options.insert("options".to_owned(), zvariant::Value::Str(Str::from(options.join(","))));
+        }
 
-        let mountpath = self.1.mount(&options).await?;
+        let mountpath = self.1.mount(&mount_options).await?;
         Ok(PathBuf::from(mountpath))
     }
 
@@ -234,8 +310,12 @@ impl<'a> MountableDevice<'a> {
         self.1.unmount(&HashMap::new()).await
     }
 
-    pub async fn id(&self) -> Result<String, zbus::Error> {
-        self.0.id().await
+    pub async fn fs_type(&self) -> Result<String, zbus::Error> {
+        self.0.fs_type().await
+    }
+
+    pub fn path(&self) -> &OwnedObjectPath {
+        self.0.path()
     }
 }
 
@@ -286,6 +366,15 @@ mod udisks2 {
 
         #[zbus(property)]
         fn id(&self) -> Result<String>;
+
+        #[zbus(property)]
+        fn id_type(&self) -> Result<String>;
+
+        #[zbus(property, name = "IdUUID")]
+        fn id_uuid(&self) -> Result<String>;
+
+        #[zbus(property)]
+        fn id_label(&self) -> Result<String>;
     }
 
     #[proxy(
@@ -306,7 +395,21 @@ mod udisks2 {
         default_service = "org.freedesktop.UDisks2"
     )]
     pub trait Drive {
+        async fn power_off(&self, options: &HashMap<String, zvariant::Value<'_>>) -> Result<()>;
+
         #[zbus(property)]
         fn removable(&self) -> Result<bool>;
+
+        #[zbus(property)]
+        fn can_power_off(&self) -> Result<bool>;
+
+        #[zbus(property)]
+        fn serial(&self) -> Result<String>;
+
+        #[zbus(property)]
+        fn vendor(&self) -> Result<String>;
+
+        #[zbus(property)]
+        fn model(&self) -> Result<String>;
     }
 }
\ No newline at end of file
@@ -0,0 +1,84 @@
+use std::{fmt::Display, path::PathBuf};
+
+use async_std::channel::{self, Receiver, Sender};
+
+/// Something that happened to a carrier, worth telling the user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CarrierEvent {
+    /// A file carrier was mounted and validated, then handed to the agent for registration
+    Connected {
+        device: String,
+        mountpoint: PathBuf
+    },
+    /// Contacts of the carrier were announced to the node
+    Registered {
+        device: String,
+        mountpoint: PathBuf,
+        contact_eids: Vec<String>,
+        /// EIDs of the nodes reachable through the contacts
+        reaches: Vec<String>
+    },
+    /// A drive was mounted but holds no file carrier, it was released
+    NotACarrier {
+        device: String,
+        mountpoint: PathBuf
+    },
+    /// The carrier is being unregistered, because it was unplugged, ejected or filtered out
+    Disconnected {
+        device: String,
+        mountpoint: PathBuf
+    },
+    /// The carrier was unregistered, unmounted and its drive powered off, it can be unplugged
+    SafeToRemove {
+        device: String,
+        mountpoint: PathBuf
+    },
+    /// A drive or carrier could not be handled
+    Failed {
+        device: String,
+        error: String
+    }
+}
+
+impl Display for CarrierEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CarrierEvent::Connected { device, mountpoint } => write!(f, "File carrier {} ({}) connected", mountpoint.display(), device),
+            CarrierEvent::Registered { mountpoint, contact_eids, .. } => write!(f, "File carrier {} registered with contacts {}", mountpoint.display(), contact_eids.join(";")),
+            CarrierEvent::NotACarrier { device, mountpoint } => write!(f, "Drive {} ({}) is not a file carrier", mountpoint.display(), device),
+            CarrierEvent::Disconnected { device, mountpoint } => write!(f, "File carrier {} ({}) disconnected", mountpoint.display(), device),
+            CarrierEvent::SafeToRemove { device, mountpoint } => write!(f, "File carrier {} ({}) can be safely removed", mountpoint.display(), device),
+            CarrierEvent::Failed { device, error } => write!(f, "Device {device}: {error}")
+        }
+    }
+}
+
+/// Dispatches [CarrierEvent]s to every subscriber
+///
+/// Subscribers are registered before the bus is cloned to emitters.
+#[derive(Debug, Clone, Default)]
+pub struct EventBus {
+    subscribers: Vec<Sender<CarrierEvent>>
+}
+
+impl EventBus {
+    /// Returns a receiver of every event emitted from now on
+    pub fn subscribe(&mut self) -> Receiver<CarrierEvent> {
+        let (sender, receiver) = channel::unbounded();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Logs an event and sends it to subscribers, without waiting for them
+    ///
+    /// Failures are not logged, as they are where they happen.
+    pub fn emit(&self, event: CarrierEvent) {
+        if !matches!(event, CarrierEvent::Failed { .. }) {
+            println!("{event}");
+        }
+        for subscriber in self.subscribers.iter() {
+            // Channels are unbounded, sending only fails if the subscriber stopped
+            let _ = subscriber.try_send(event.clone());
+        }
+    }
+}
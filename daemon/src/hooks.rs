@@ -0,0 +1,151 @@
+use std::{path::{Path, PathBuf}, process::{Command, Stdio}, time::{Duration, Instant}};
+
+use async_std::{channel::Receiver, task};
+use file_carrier::config::HookConfig;
+use futures::{stream, StreamExt};
+
+use crate::events::CarrierEvent;
+
+/// Interval between checks of running hooks
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Returns the hooks to run for an event, with the environment variables describing it
+pub fn hooks_for<'a>(event: &CarrierEvent, config: &'a HookConfig) -> (&'a [PathBuf], Vec<(&'static str, String)>) {
+    let mountpoint = |path: &Path| ("ARCHIPELFC_MOUNTPOINT", path.display().to_string());
+
+    match event {
+        CarrierEvent::Connected { device, mountpoint: path } => (&config.connected, vec![
+            ("ARCHIPELFC_EVENT", "connected".to_owned()),
+            mountpoint(path),
+            ("ARCHIPELFC_DEVICE", device.clone())
+        ]),
+        CarrierEvent::Registered { device, mountpoint: path, contact_eids, reaches } => (&config.registered, vec![
+            ("ARCHIPELFC_EVENT", "registered".to_owned()),
+            mountpoint(path),
+            ("ARCHIPELFC_DEVICE", device.clone()),
+            ("ARCHIPELFC_CONTACT_EIDS", contact_eids.join(";")),
+            ("ARCHIPELFC_REACHES", reaches.join(";"))
+        ]),
+        CarrierEvent::Disconnected { device, mountpoint: path } => (&config.disconnected, vec![
+            ("ARCHIPELFC_EVENT", "disconnected".to_owned()),
+            mountpoint(path),
+            ("ARCHIPELFC_DEVICE", device.clone())
+        ]),
+        CarrierEvent::Failed { device, error } => (&config.error, vec![
+            ("ARCHIPELFC_EVENT", "error".to_owned()),
+            ("ARCHIPELFC_DEVICE", device.clone()),
+            ("ARCHIPELFC_ERROR", error.clone())
+        ]),
+        CarrierEvent::NotACarrier { .. } | CarrierEvent::SafeToRemove { .. } => (&[], Vec::new())
+    }
+}
+
+enum HookMessage {
+    Event(CarrierEvent),
+    Reconfigure(HookConfig)
+}
+
+/// Runs the configured hooks for every event, until `events` is closed
+///
+/// Hooks run in the background, so a slow hook neither delays the daemon nor the next hooks.
+pub async fn run_hooks(events: Receiver<CarrierEvent>, configs: Receiver<HookConfig>, mut config: HookConfig) {
+    let mut messages = stream::select(
+        events.map(HookMessage::Event),
+        configs.map(HookMessage::Reconfigure)
+    );
+
+    while let Some(message) = messages.next().await {
+        let event = match message {
+            HookMessage::Reconfigure(new_config) => {
+                config = new_config;
+                continue;
+            },
+            HookMessage::Event(event) => event
+        };
+
+        let (hooks, environment) = hooks_for(&event, &config);
+        for hook in hooks {
+            task::spawn(run_hook(hook.clone(), environment.clone(), config.timeout()));
+        }
+    }
+}
+
+/// Runs a hook, killing it if it is still running after `timeout`
+async fn run_hook(hook: PathBuf, environment: Vec<(&'static str, String)>, timeout: Duration) {
+    let mut child = match Command::new(&hook).envs(environment).stdin(Stdio::null()).spawn() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Failed to run hook {}: {e}", hook.display());
+            return;
+        }
+    };
+
+    let started = Instant::now();
+    loop {
+        match child.try_wait() {
+            Err(e) => {
+                eprintln!("Failed to wait for hook {}: {e}", hook.display());
+                return;
+            },
+            Ok(Some(status)) => {
+                if !status.success() {
+                    eprintln!("Hook {} exited with {status}", hook.display());
+                }
+                return;
+            },
+            Ok(None) if started.elapsed() >= timeout => {
+                eprintln!("Hook {} still running after {} seconds, killing it", hook.display(), timeout.as_secs());
+                let _ = child.kill();
+                let _ = child.wait();
+                return;
+            },
+            Ok(None) => task::sleep(POLL_INTERVAL).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use file_carrier::config::HookConfig;
+
+    use crate::{events::CarrierEvent, hooks::hooks_for};
+
+    #[test]
+    fn hook_environment() {
+        let config = HookConfig {
+            registered: vec![PathBuf::from("/usr/local/bin/blink-led")],
+            error: vec![PathBuf::from("/usr/local/bin/alert")],
+            ..HookConfig::default()
+        };
+
+        let registered = CarrierEvent::Registered {
+            device: "by-uuid-1".to_owned(),
+            mountpoint: PathBuf::from("/media/carrier"),
+            contact_eids: vec!["dtn://node-a/".to_owned()],
+            reaches: vec!["dtn://node-b/".to_owned(), "dtn://node-c/".to_owned()]
+        };
+        let (hooks, environment) = hooks_for(&registered, &config);
+        assert_eq!(hooks, [Path::new("/usr/local/bin/blink-led")]);
+        assert_eq!(environment, vec![
+            ("ARCHIPELFC_EVENT", "registered".to_owned()),
+            ("ARCHIPELFC_MOUNTPOINT", "/media/carrier".to_owned()),
+            ("ARCHIPELFC_DEVICE", "by-uuid-1".to_owned()),
+            ("ARCHIPELFC_CONTACT_EIDS", "dtn://node-a/".to_owned()),
+            ("ARCHIPELFC_REACHES", "dtn://node-b/;dtn://node-c/".to_owned())
+        ]);
+
+        let connected = CarrierEvent::Connected { device: "by-uuid-1".to_owned(), mountpoint: PathBuf::from("/media/carrier") };
+        assert!(hooks_for(&connected, &config).0.is_empty());
+
+        let failed = CarrierEvent::Failed { device: "by-uuid-1".to_owned(), error: "failed to register: no reaches file".to_owned() };
+        let (hooks, environment) = hooks_for(&failed, &config);
+        assert_eq!(hooks, [Path::new("/usr/local/bin/alert")]);
+        assert_eq!(environment, vec![
+            ("ARCHIPELFC_EVENT", "error".to_owned()),
+            ("ARCHIPELFC_DEVICE", "by-uuid-1".to_owned()),
+            ("ARCHIPELFC_ERROR", "failed to register: no reaches file".to_owned())
+        ]);
+    }
+}
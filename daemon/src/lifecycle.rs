@@ -0,0 +1,394 @@
+use std::{ffi::{CStr, CString}, fmt::Display, fs, io, mem, os::unix::{ffi::OsStrExt, fs::MetadataExt}, path::{Path, PathBuf}, ptr, time::{Duration, SystemTime}};
+
+use file_carrier::{config::{DeviceIdentity, MountConfig}, hierarchy::FileCarrierHierarchy, init::initialize_file_carrier};
+use zbus::zvariant::OwnedObjectPath;
+
+use crate::disks::MountableDevice;
+
+/// Lifecycle state of a device handled by the daemon
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceState {
+    /// A mountable removable device was plugged in
+    Detected,
+    /// Its filesystem is mounted
+    Mounted,
+    /// Its filesystem holds a file carrier
+    Validated,
+    /// The carrier was handed to the agent for registration
+    Registered,
+    /// The carrier is being unregistered, it goes back to [DeviceState::Validated] if it stays mounted
+    Draining,
+    /// The daemon released the device : unplugged, unmounted, or not a file carrier
+    Ejected,
+    /// Handling the device failed, the daemon no longer acts on it until it is unplugged
+    Failed
+}
+
+impl DeviceState {
+    /// Returns `true` if a device in this state may move to `next`
+    pub fn can_become(self, next: DeviceState) -> bool {
+        use DeviceState::*;
+
+        match (self, next) {
+            (Ejected, _) => false,
+            (Failed, next) => next == Ejected,
+            (_, Failed) | (_, Ejected) => true,
+            (Detected, Mounted) | (Mounted, Validated) | (Validated, Registered) | (Registered, Draining) | (Draining, Validated) => true,
+            _ => false
+        }
+    }
+}
+
+impl Display for DeviceState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            DeviceState::Detected => "detected",
+            DeviceState::Mounted => "mounted",
+            DeviceState::Validated => "validated",
+            DeviceState::Registered => "registered",
+            DeviceState::Draining => "draining",
+            DeviceState::Ejected => "ejected",
+            DeviceState::Failed => "failed"
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Failure while handling a single device
+#[derive(Debug)]
+pub enum DeviceError {
+    Dbus(zbus::Error),
+    IO(io::Error),
+    /// The carrier is mounted by someone else on this mountpoint, which the core user can't write to
+    NotWritable(PathBuf),
+    /// The agent task stopped and can't take messages anymore
+    AgentStopped
+}
+
+impl Display for DeviceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceError::Dbus(e) => write!(f, "D-Bus error: {e}"),
+            DeviceError::IO(e) => write!(f, "IO error: {e}"),
+            DeviceError::NotWritable(mountpoint) => write!(f, "{} is mounted by another user and not writable by the core user, unmount it to let the daemon mount it", mountpoint.display()),
+            DeviceError::AgentStopped => write!(f, "agent task stopped")
+        }
+    }
+}
+
+impl From<zbus::Error> for DeviceError {
+    fn from(value: zbus::Error) -> Self {
+        DeviceError::Dbus(value)
+    }
+}
+
+impl From<io::Error> for DeviceError {
+    fn from(value: io::Error) -> Self {
+        DeviceError::IO(value)
+    }
+}
+
+/// A device followed by the daemon through its [DeviceState]s
+///
+/// Every transition is logged, invalid ones are refused.
+pub struct ManagedDevice<'a> {
+    id: String,
+    identity: DeviceIdentity,
+    device: MountableDevice<'a>,
+    state: DeviceState,
+    mountpoint: Option<PathBuf>,
+    /// `true` if the daemon mounted the device itself, and has to unmount it
+    mounted_by_us: bool,
+    /// Date of the move to [DeviceState::Registered]
+    registered_at: Option<SystemTime>
+}
+
+impl<'a> ManagedDevice<'a> {
+    pub fn detect(device: MountableDevice<'a>, identity: DeviceIdentity) -> Self {
+        let id = if identity.id.is_empty() {
+            device.path().to_string()
+        } else {
+            identity.id.clone()
+        };
+
+        println!("Device {id}: {}", DeviceState::Detected);
+
+        Self { id, identity, device, state: DeviceState::Detected, mountpoint: None, mounted_by_us: false, registered_at: None }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn identity(&self) -> &DeviceIdentity {
+        &self.identity
+    }
+
+    pub fn path(&self) -> &OwnedObjectPath {
+        self.device.path()
+    }
+
+    pub fn state(&self) -> DeviceState {
+        self.state
+    }
+
+    pub fn mountpoint(&self) -> Option<&Path> {
+        self.mountpoint.as_deref()
+    }
+
+    /// Moves the device to `next`, returns `false` if the transition is not allowed
+    pub fn transition(&mut self, next: DeviceState) -> bool {
+        if !self.state.can_become(next) {
+            eprintln!("Device {}: refused transition {} -> {}", self.id, self.state, next);
+            return false;
+        }
+
+        println!("Device {}: {} -> {}", self.id, self.state, next);
+        self.state = next;
+        if next == DeviceState::Registered {
+            self.registered_at = Some(SystemTime::now());
+        }
+        true
+    }
+
+    /// Returns `true` if the device is registered and no bundle was written to or removed from the carrier
+    /// during `period`, since its registration
+    pub fn is_quiescent(&self, period: Duration, now: SystemTime) -> bool {
+        let (DeviceState::Registered, Some(registered_at), Some(mountpoint)) = (self.state, self.registered_at, self.mountpoint.as_ref()) else {
+            return false;
+        };
+
+        let Ok(last_activity) = FileCarrierHierarchy::new(mountpoint).last_data_activity() else {
+            return false;
+        };
+
+        now.duration_since(registered_at.max(last_activity))
+            .is_ok_and(|quiet| quiet >= period)
+    }
+
+    /// Moves the device to [DeviceState::Failed], logging why
+    pub fn fail(&mut self, error: impl Display) {
+        eprintln!("Device {}: {error}", self.id);
+        self.transition(DeviceState::Failed);
+    }
+
+    /// Uses the current mountpoint of the device, or mounts it
+    pub async fn mount(&mut self, config: &MountConfig) -> Result<(), DeviceError> {
+        let (mountpoint, mounted_by_us) = match self.device.mountpoints().await?.into_iter().next() {
+            Some(m) => (m, false),
+            None => (self.mount_filesystem(config).await?, true)
+        };
+
+        self.mountpoint = Some(mountpoint);
+        self.mounted_by_us = mounted_by_us;
+        self.transition(DeviceState::Mounted);
+        Ok(())
+    }
+
+    /// Checks whether the mounted filesystem is a file carrier, moving to [DeviceState::Validated] if it is
+    pub fn validate(&mut self) -> Result<bool, DeviceError> {
+        let Some(mountpoint) = self.mountpoint.as_ref() else {
+            return Ok(false);
+        };
+
+        if !FileCarrierHierarchy::folder_is_file_carrier(mountpoint)? {
+            println!("Device {} is not a file carrier", self.id);
+            return Ok(false);
+        }
+
+        Ok(self.transition(DeviceState::Validated))
+    }
+
+    /// Creates a file carrier hierarchy on the mounted filesystem
+    pub fn initialize(&mut self) -> Result<(), DeviceError> {
+        let Some(mountpoint) = self.mountpoint.as_ref() else {
+            return Ok(());
+        };
+
+        initialize_file_carrier(mountpoint)?;
+        println!("Device {}: initialized as a file carrier", self.id);
+        Ok(())
+    }
+
+    /// Makes sure the carrier is writable by the core user
+    ///
+    /// A carrier mounted by someone else, such as a desktop automounter, is never remounted under its feet :
+    /// its mountpoint is reused if the core user can write to it, refused otherwise.
+    pub fn ensure_writable(&self, config: &MountConfig) -> Result<(), DeviceError> {
+        let (false, Some(mountpoint)) = (self.mounted_by_us, self.mountpoint.as_ref()) else {
+            return Ok(());
+        };
+
+        let hierarchy = FileCarrierHierarchy::new(mountpoint);
+        let folder = if hierarchy.try_exists()? { hierarchy.root() } else { mountpoint.as_path() };
+
+        if !is_writable_by(folder, config.as_user.as_deref())? {
+            return Err(DeviceError::NotWritable(mountpoint.clone()));
+        }
+
+        println!("Device {}: reusing existing mountpoint {}", self.id, mountpoint.display());
+        Ok(())
+    }
+
+    /// Unmounts the device if the daemon mounted it, then moves it to [DeviceState::Ejected]
+    pub async fn release(&mut self) -> Result<(), DeviceError> {
+        if self.mounted_by_us && self.mountpoint.is_some() {
+            self.device.unmount().await?;
+            self.mounted_by_us = false;
+        }
+
+        self.transition(DeviceState::Ejected);
+        Ok(())
+    }
+
+    /// Unmounts the device, even if the daemon did not mount it, and powers its drive off when possible,
+    /// then moves it to [DeviceState::Ejected]
+    pub async fn eject(&mut self) -> Result<(), DeviceError> {
+        if self.mountpoint.is_some() {
+            self.device.unmount().await?;
+            self.mounted_by_us = false;
+        }
+
+        if let Some(drive) = self.device.drive().await? {
+            if drive.can_power_off().await? {
+                drive.power_off().await?;
+            }
+        }
+
+        self.transition(DeviceState::Ejected);
+        Ok(())
+    }
+
+    async fn mount_filesystem(&self, config: &MountConfig) -> Result<PathBuf, DeviceError> {
+        let fs_type = self.device.fs_type().await?;
+        let options = config.options_for(&fs_type, user_ids(config.as_user.as_deref())?);
+
+        Ok(self.device.mount(config.as_user.as_deref(), &options).await?)
+    }
+}
+
+/// Returns `true` if `user`, or the user running the daemon if `None`, may write into `folder`
+///
+/// Access of the user running the daemon is checked by the kernel. For another user, permission bits are checked
+/// against its primary and supplementary groups.
+pub fn is_writable_by(folder: &Path, user: Option<&str>) -> io::Result<bool> {
+    let (uid, gid) = user_ids(user)?;
+
+    let Some(user) = user.filter(|_| uid != unsafe { libc::geteuid() }) else {
+        let path = CString::new(folder.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        if unsafe { libc::faccessat(libc::AT_FDCWD, path.as_ptr(), libc::W_OK, libc::AT_EACCESS) } == 0 {
+            return Ok(true);
+        }
+        return match io::Error::last_os_error() {
+            e if matches!(e.raw_os_error(), Some(libc::EACCES | libc::EROFS)) => Ok(false),
+            e => Err(e)
+        };
+    };
+
+    if uid == 0 {
+        return Ok(true);
+    }
+
+    let metadata = fs::metadata(folder)?;
+    let mode = metadata.mode();
+    let writable = if metadata.uid() == uid {
+        mode & 0o200 != 0
+    } else if user_groups(&CString::new(user.as_bytes())?, gid)?.contains(&metadata.gid()) {
+        mode & 0o020 != 0
+    } else {
+        mode & 0o002 != 0
+    };
+
+    Ok(writable)
+}
+
+/// Primary group `gid` and supplementary groups of the user `name`
+fn user_groups(name: &CStr, gid: u32) -> io::Result<Vec<u32>> {
+    let mut groups = vec![0; 32];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        if unsafe { libc::getgrouplist(name.as_ptr(), gid, groups.as_mut_ptr(), &mut count) } >= 0 {
+            groups.truncate(count as usize);
+            return Ok(groups);
+        }
+        // `count` holds the number of groups of the user
+        groups.resize((count as usize).max(groups.len() * 2), 0);
+    }
+}
+
+/// User and primary group ids of `user`, or of the user running the daemon if `None`
+fn user_ids(user: Option<&str>) -> io::Result<(u32, u32)> {
+    let Some(user) = user else {
+        return Ok(unsafe { (libc::geteuid(), libc::getegid()) });
+    };
+
+    let name = CString::new(user.as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    // Strings of the entry are stored in `buffer`, grown until they fit
+    let mut entry: libc::passwd = unsafe { mem::zeroed() };
+    let mut result = ptr::null_mut();
+    let mut buffer = vec![0; 1024];
+    loop {
+        let status = unsafe { libc::getpwnam_r(name.as_ptr(), &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+        match status {
+            libc::ERANGE => buffer.resize(buffer.len() * 2, 0),
+            0 if result.is_null() => return Err(io::Error::new(io::ErrorKind::NotFound, format!("unknown user {user}"))),
+            0 => return Ok((entry.pw_uid, entry.pw_gid)),
+            e => return Err(io::Error::from_raw_os_error(e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::CString, fs, os::unix::fs::PermissionsExt};
+
+    use file_carrier::testing::TempFolder;
+
+    use crate::lifecycle::{is_writable_by, user_groups, user_ids, DeviceState::{self, *}};
+
+    #[test]
+    fn nominal_lifecycle() {
+        let path = [Detected, Mounted, Validated, Registered, Draining, Ejected];
+        for pair in path.windows(2) {
+            assert!(pair[0].can_become(pair[1]), "{} -> {}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn final_states() {
+        let all = [Detected, Mounted, Validated, Registered, Draining, Ejected, Failed];
+        for state in all {
+            assert!(!Ejected.can_become(state));
+            assert_eq!(Failed.can_become(state), state == Ejected);
+        }
+    }
+
+    #[test]
+    fn no_skipped_steps() {
+        assert!(!Detected.can_become(Registered));
+        assert!(!Mounted.can_become(Draining));
+        assert!(!Registered.can_become(Mounted));
+        assert!(Draining.can_become(Validated));
+        assert!(Registered.can_become(DeviceState::Failed));
+    }
+
+    #[test]
+    fn writable_folders() {
+        assert_eq!(user_ids(Some("root")).unwrap(), (0, 0));
+        assert!(user_ids(Some("no-such-user-archipel")).is_err());
+        assert!(user_groups(&CString::new("root").unwrap(), 0).unwrap().contains(&0));
+
+        let folder = TempFolder::new("fcd-writable").unwrap();
+        assert!(is_writable_by(folder.path(), None).unwrap());
+        assert!(is_writable_by(folder.path(), Some("root")).unwrap());
+
+        // Owned by the current user, writable by nobody else
+        fs::set_permissions(folder.path(), fs::Permissions::from_mode(0o755)).unwrap();
+        let (uid, _) = user_ids(None).unwrap();
+        let other = if uid == 0 { "nobody" } else { "root" };
+        assert_eq!(is_writable_by(folder.path(), Some(other)).unwrap(), other == "root");
+    }
+}
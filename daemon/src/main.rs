@@ -1,176 +1,718 @@
-use std::{path::{Path, PathBuf}, time::Duration};
-
-use async_std::{channel::{self, Receiver, RecvError, Sender}, task};
-use disks::{DiskManager, IntoMountableDeviceError, MountableDevice};
-use file_carrier::{hierarchy::FileCarrierHierarchy, register::register_folder};
-use futures::{future::try_join_all, StreamExt};
-use ud3tn_aap::{AapStream, Agent, RegisteredAgent};
-use zbus::Connection;
+use std::{collections::HashMap, fmt::Display, fs, io, mem, path::{Path, PathBuf}, process, thread, time::{Duration, SystemTime}};
+
+use async_std::{channel::{self, Receiver, RecvError, Sender}, future, task::{self, JoinHandle}};
+use connection::CoreConnection;
+use disks::{BlockDevice, DiskManager, IntoMountableDeviceError, MountableDevice};
+use file_carrier::{config::{Config, DeviceIdentity}, error::FileCarrierError, hierarchy::FileCarrierHierarchy, register::{announce_contact, register_folder, RegistrationReport}, strategy::ContactStrategy, unregister::{delete_contacts, unregister_folder}};
+use futures::{future::join_all, stream, StreamExt};
+use events::{CarrierEvent, EventBus};
+use lifecycle::{DeviceError, DeviceState, ManagedDevice};
+use service::{CarrierStatus, ServiceRequest};
+use zbus::{zvariant::OwnedObjectPath, Connection};
 use clap::Parser;
+use signal_hook::{consts::{SIGHUP, SIGINT, SIGTERM}, iterator::Signals};
 
+mod connection;
 mod disks;
+mod events;
+mod hooks;
+mod lifecycle;
+mod notify;
+mod service;
+mod systemd;
 
-async fn async_main(cli: Cli) {
+/// Maximum time given to unregister, flush and unmount carriers when stopping
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(20);
 
-    let config_agent = Agent::connect_unix(
-        Path::new("/run/archipel-core/archipel-core.socket")
-    )
-        .expect("Failed to connect to Archipel Core")
-        .register("file-carrier/config-daemon".to_owned())
-        .expect("Failed to register config agent");
+/// Interval between checks of registered carriers activity
+const QUIESCENCE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
 
-    let (agent_message_sender, agent_task) = {
-        let (sender, receiver) = channel::unbounded::<AgentMessage>();
-        let task = task::spawn(agent_task(receiver, config_agent));
-        (sender, task)
-    };
+async fn async_main(cli: Cli, mut config: Config) {
 
     let dbus = Connection::system().await
         .expect("Failed to connect to dbus");
 
+    let mut event_bus = EventBus::default();
+    let (request_sender, requests) = channel::unbounded::<ServiceRequest>();
+    if let Err(e) = service::serve(&dbus, request_sender, event_bus.subscribe()).await {
+        eprintln!("Failed to expose {} on D-Bus, continuing without it: {e}", service::SERVICE_NAME);
+    }
+
+    // Only available when running in a user session, as a user service
+    let notifier = match Connection::session().await {
+        Err(e) => {
+            println!("No session bus, desktop notifications disabled: {e}");
+            None
+        },
+        Ok(session) => {
+            let (sender, receiver) = channel::unbounded();
+            task::spawn(notify::notify(session, event_bus.subscribe(), receiver, config.notifications.clone()));
+            Some(sender)
+        }
+    };
+
+    let hooks = {
+        let (sender, receiver) = channel::unbounded();
+        task::spawn(hooks::run_hooks(event_bus.subscribe(), receiver, config.hooks.clone()));
+        sender
+    };
+
+    let (context, agent_task, registrations) = {
+        let (sender, receiver) = channel::unbounded::<AgentMessage>();
+        let (outcome_sender, outcomes) = channel::unbounded::<RegistrationOutcome>();
+        let task = task::spawn(agent_task(receiver, config.clone(), outcome_sender));
+        (DaemonContext { agent: sender, events: event_bus }, task, outcomes)
+    };
+
     let manager = DiskManager::new(&dbus).await
         .expect("Failed to connect to udisks2");
 
-    init_all_devices(&manager, agent_message_sender.clone(), cli.as_user.clone()).await
-        .expect("Failed to init all devices");
-    
-    let mut added_devices = manager.devices_added().await
+    let signals = watch_signals()
+        .expect("Failed to watch signals");
+
+    // Devices followed by the daemon, until they are unplugged
+    let mut devices = init_all_devices(&manager, &context, &config).await
+        .expect("Failed to list block devices");
+
+    systemd::notify("READY=1");
+
+    let added_devices = manager.devices_added().await
         .expect("Failed to watch added devices");
 
+    let removed_devices = manager.devices_removed().await
+        .expect("Failed to watch removed devices");
+
+    let ticks = Box::pin(stream::unfold((), async |()| {
+        task::sleep(QUIESCENCE_CHECK_INTERVAL).await;
+        Some((DaemonEvent::Tick, ()))
+    }));
+
+    // Pings are sent twice per interval, as recommended by systemd
+    let watchdog_pings = match systemd::watchdog_interval() {
+        None => stream::pending().left_stream(),
+        Some(interval) => Box::pin(stream::unfold(interval / 2, async |period| {
+            task::sleep(period).await;
+            Some((DaemonEvent::Watchdog, period))
+        })).right_stream()
+    };
+
+    let mut daemon_events = stream::select(
+        stream::select(
+            added_devices.map(DaemonEvent::Added),
+            removed_devices.map(DaemonEvent::Removed)
+        ),
+        stream::select(
+            stream::select(signals.map(DaemonEvent::Signal), requests.map(DaemonEvent::Request)),
+            stream::select(
+                registrations.map(DaemonEvent::Registration),
+                stream::select(ticks, watchdog_pings)
+            )
+        )
+    );
+
+    let mut status = String::new();
+    // Answered once the agent handled every message sent before the last watchdog ping
+    let mut agent_barrier: Option<Receiver<()>> = None;
+
     loop {
-        let Some(added_device) = added_devices.next().await else {
-            break
-        };
+        let current_status = carriers_status(&devices);
+        if current_status != status {
+            systemd::notify(&format!("STATUS={current_status}"));
+            status = current_status;
+        }
 
-        let device = match added_device.try_into_mountable().await {
-            Err(IntoMountableDeviceError::NotMountable) => continue,
-            Err(IntoMountableDeviceError::Dbus(e)) => panic!("Failed to get mountable device: {e}"),
-            Ok(d) => d
+        let Some(event) = daemon_events.next().await else {
+            break;
         };
 
-        let Some(drive) = device.drive().await
-            .expect("Failed to get drive") else {
-            continue;
-        };
+        match event {
+            DaemonEvent::Added(added_device) => {
+                let device_path = added_device.path().clone();
+
+                let (device, identity) = match removable_mountable(added_device, &config).await {
+                    Err(e) => {
+                        eprintln!("Failed to inspect device {}: {e}", device_path.as_str());
+                        continue;
+                    },
+                    Ok(None) => continue,
+                    Ok(Some(d)) => d
+                };
+
+                let device = handle_device(device, identity, &context, &config).await;
+                if device.state() != DeviceState::Ejected {
+                    devices.insert(device_path, device);
+                }
+            },
+            DaemonEvent::Removed(device_path) => {
+                let Some(mut device) = devices.remove(&device_path) else {
+                    continue;
+                };
+
+                withdraw(&mut device, &context).await;
+                device.transition(DeviceState::Ejected);
+            },
+            DaemonEvent::Registration(outcome) => handle_registration(outcome, &mut devices, &context),
+            DaemonEvent::Tick => {
+                let Some(period) = config.daemon.eject_after() else {
+                    continue;
+                };
+
+                let now = SystemTime::now();
+                let quiescent: Vec<OwnedObjectPath> = devices.values()
+                    .filter(|device| device.is_quiescent(period, now))
+                    .map(|device| device.path().clone())
+                    .collect();
+
+                for path in quiescent {
+                    let Some(device) = devices.get_mut(&path) else {
+                        continue;
+                    };
+
+                    println!("Device {}: no bundle transfer for {} seconds", device.id(), period.as_secs());
+                    safe_eject(device, &context).await;
+                    // A drive which failed to eject is still plugged in, it is followed until unplugged
+                    if device.state() == DeviceState::Ejected {
+                        devices.remove(&path);
+                    }
+                }
+            },
+            DaemonEvent::Watchdog => {
+                // Pings stop while the agent is stuck, so systemd restarts the daemon
+                if agent_barrier.as_ref().is_some_and(|barrier| barrier.try_recv().is_err()) {
+                    eprintln!("Agent task is not responding, watchdog not pinged");
+                    continue;
+                }
+
+                systemd::notify("WATCHDOG=1");
+                let (sender, receiver) = channel::bounded(1);
+                // If the agent stopped, the barrier is dropped and never answered
+                let _ = context.agent.send(AgentMessage::Barrier(sender)).await;
+                agent_barrier = Some(receiver);
+            },
+            DaemonEvent::Request(request) => handle_request(request, &mut devices, &context).await,
+            DaemonEvent::Signal(SIGHUP) => {
+                let new_config = match cli.load_config() {
+                    Ok(c) => c,
+                    Err(e) => {
+                        eprintln!("Failed to reload configuration, keeping the current one: {e}");
+                        continue;
+                    }
+                };
+                println!("Configuration reloaded");
+
+                let previous = mem::replace(&mut config, new_config);
+                if context.agent.send(AgentMessage::Reconfigure(Box::new(config.clone()))).await.is_err() {
+                    eprintln!("Agent task stopped, new configuration only applies to devices");
+                }
+                let _ = hooks.send(config.hooks.clone()).await;
+                if let Some(notifier) = &notifier {
+                    let _ = notifier.send(config.notifications.clone()).await;
+                }
+
+                apply_device_filters(&manager, &mut devices, &previous, &config, &context).await;
+            },
+            DaemonEvent::Signal(signal) => {
+                println!("Received signal {signal}, shutting down");
+                break;
+            }
+        }
+    }
+
+    // D-Bus requests are refused from now on
+    drop(daemon_events);
+    systemd::notify("STOPPING=1");
+
+    if future::timeout(SHUTDOWN_TIMEOUT, shutdown(devices, context, agent_task)).await.is_err() {
+        eprintln!("Shutdown did not complete in {} seconds, exiting anyway", SHUTDOWN_TIMEOUT.as_secs());
+    }
+}
 
-        if !drive.removable().await.expect("Failed to check for removable drive") {
+/// Unregisters every carrier, then stops the agent and unmounts drives mounted by the daemon
+async fn shutdown(mut devices: HashMap<OwnedObjectPath, ManagedDevice<'_>>, context: DaemonContext, agent_task: JoinHandle<()>) {
+    for device in devices.values_mut() {
+        withdraw(device, &context).await;
+    }
+
+    // Messages are handled in order, so every carrier is unregistered once the agent is stopped
+    let _ = context.agent.send(AgentMessage::Shutdown).await;
+    agent_task.await;
+
+    unsafe {
+        libc::sync();
+    }
+
+    for device in devices.values_mut() {
+        if let Err(e) = device.release().await {
+            fail(device, format!("failed to unmount: {e}"), &context);
+        }
+    }
+}
+
+/// Channels used to act on carriers
+struct DaemonContext {
+    agent: Sender<AgentMessage>,
+    events: EventBus
+}
+
+/// Moves the device to [DeviceState::Failed] and tells subscribers why
+fn fail(device: &mut ManagedDevice<'_>, error: impl Display, context: &DaemonContext) {
+    let error = error.to_string();
+    device.fail(&error);
+    context.events.emit(CarrierEvent::Failed { device: device.id().to_owned(), error });
+}
+
+/// Moves a carrier to [DeviceState::Registered] once the agent announced its contacts, or fails it
+///
+/// Outcomes of carriers unplugged, ejected or filtered out meanwhile are ignored.
+fn handle_registration(outcome: RegistrationOutcome, devices: &mut HashMap<OwnedObjectPath, ManagedDevice<'_>>, context: &DaemonContext) {
+    let mountpoint = match &outcome {
+        RegistrationOutcome::Registered { mountpoint, .. } | RegistrationOutcome::FirstUse(mountpoint) | RegistrationOutcome::Failed { mountpoint, .. } => mountpoint
+    };
+
+    let Some(device) = devices.values_mut()
+        .find(|device| device.state() == DeviceState::Validated && device.mountpoint() == Some(mountpoint.as_path())) else {
+        return;
+    };
+
+    match outcome {
+        RegistrationOutcome::Registered { mountpoint, contact_eids, reaches } => {
+            device.transition(DeviceState::Registered);
+            context.events.emit(CarrierEvent::Registered { device: device.id().to_owned(), mountpoint, contact_eids, reaches });
+        },
+        // Nothing was announced, the carrier stays validated
+        RegistrationOutcome::FirstUse(_) => {},
+        RegistrationOutcome::Failed { error, .. } => fail(device, format!("failed to register: {error}"), context)
+    }
+}
+
+/// Handles a request of a D-Bus client
+async fn handle_request(request: ServiceRequest, devices: &mut HashMap<OwnedObjectPath, ManagedDevice<'_>>, context: &DaemonContext) {
+    let find = |devices: &HashMap<OwnedObjectPath, ManagedDevice<'_>>, mountpoint: &Path| devices.values()
+        .find(|device| device.mountpoint() == Some(mountpoint))
+        .map(|device| device.path().clone())
+        .ok_or_else(|| format!("no file carrier mounted on {}", mountpoint.display()));
+
+    match request {
+        ServiceRequest::ListCarriers(reply) => {
+            let _ = reply.send(devices.values().map(carrier_status).collect()).await;
+        },
+        ServiceRequest::Register(mountpoint, reply) => {
+            let result = match find(devices, &mountpoint) {
+                Err(e) => Err(e),
+                Ok(path) => {
+                    let device = devices.get_mut(&path).expect("device was just found");
+                    if device.state() != DeviceState::Validated {
+                        Err(format!("file carrier {} is {}", mountpoint.display(), device.state()))
+                    } else {
+                        register(device, context).await.map_err(|e| e.to_string())
+                    }
+                }
+            };
+            let _ = reply.send(result).await;
+        },
+        ServiceRequest::Unregister(mountpoint, reply) => {
+            let result = match find(devices, &mountpoint) {
+                Err(e) => Err(e),
+                Ok(path) => {
+                    let device = devices.get_mut(&path).expect("device was just found");
+                    if device.state() != DeviceState::Registered {
+                        Err(format!("file carrier {} is {}", mountpoint.display(), device.state()))
+                    } else {
+                        drain(device, context).await;
+                        wait_for_agent(context).await;
+                        // Draining fails if the agent stopped
+                        match device.state() {
+                            DeviceState::Draining => {
+                                device.transition(DeviceState::Validated);
+                                Ok(())
+                            },
+                            state => Err(format!("file carrier {} is {}", mountpoint.display(), state))
+                        }
+                    }
+                }
+            };
+            let _ = reply.send(result).await;
+        },
+        ServiceRequest::Eject(mountpoint, reply) => {
+            let result = match find(devices, &mountpoint) {
+                Err(e) => Err(e),
+                Ok(path) => {
+                    let mut device = devices.remove(&path).expect("device was just found");
+                    safe_eject(&mut device, context).await;
+                    match device.state() {
+                        DeviceState::Ejected => Ok(()),
+                        state => {
+                            devices.insert(path, device);
+                            Err(format!("file carrier {} is {}", mountpoint.display(), state))
+                        }
+                    }
+                }
+            };
+            let _ = reply.send(result).await;
+        }
+    }
+}
+
+/// Summary of registered carriers for systemd
+fn carriers_status(devices: &HashMap<OwnedObjectPath, ManagedDevice<'_>>) -> String {
+    let mut mountpoints: Vec<String> = devices.values()
+        .filter(|device| device.state() == DeviceState::Registered)
+        .filter_map(|device| device.mountpoint())
+        .map(|mountpoint| mountpoint.display().to_string())
+        .collect();
+    mountpoints.sort();
+
+    match mountpoints.len() {
+        0 => "No file carrier registered".to_owned(),
+        n => format!("{n} file carrier(s) registered: {}", mountpoints.join(", "))
+    }
+}
+
+/// State of a device for D-Bus clients
+fn carrier_status(device: &ManagedDevice<'_>) -> CarrierStatus {
+    let mountpoint = device.mountpoint().map(Path::to_path_buf).unwrap_or_default();
+
+    let contact_eids = match device.state() {
+        DeviceState::Registered => fs::read_to_string(FileCarrierHierarchy::new(&mountpoint).connected_file())
+            .map(|content| content.lines().map(str::trim).filter(|l| !l.is_empty()).map(str::to_owned).collect())
+            .unwrap_or_default(),
+        _ => Vec::new()
+    };
+
+    CarrierStatus {
+        mountpoint,
+        device: device.id().to_owned(),
+        state: device.state().to_string(),
+        contact_eids
+    }
+}
+
+/// Unregisters a carrier if it is registered or being registered, then unmounts and powers off its drive so it can be unplugged
+async fn safe_eject(device: &mut ManagedDevice<'_>, context: &DaemonContext) {
+    if withdraw(device, context).await {
+        wait_for_agent(context).await;
+    }
+
+    unsafe {
+        libc::sync();
+    }
+
+    match device.eject().await {
+        Err(e) => fail(device, format!("failed to eject: {e}"), context),
+        Ok(()) => context.events.emit(CarrierEvent::SafeToRemove {
+            device: device.id().to_owned(),
+            mountpoint: device.mountpoint().map(Path::to_path_buf).unwrap_or_default()
+        })
+    }
+}
+
+/// Waits until the agent handled every message sent before
+async fn wait_for_agent(context: &DaemonContext) {
+    let (sender, receiver) = channel::bounded(1);
+    if context.agent.send(AgentMessage::Barrier(sender)).await.is_ok() {
+        let _ = receiver.recv().await;
+    }
+}
+
+/// Drains a registered carrier, or cancels the registration of a validated one the agent may not have handled yet
+///
+/// Returns `true` if the agent was asked to unregister the carrier.
+async fn withdraw(device: &mut ManagedDevice<'_>, context: &DaemonContext) -> bool {
+    match (device.state(), device.mountpoint()) {
+        (DeviceState::Registered, Some(_)) => {
+            drain(device, context).await;
+            true
+        },
+        // Unregistering a carrier the agent does not know is harmless
+        (DeviceState::Validated, Some(mountpoint)) => context.agent.send(AgentMessage::Unregister(mountpoint.to_path_buf())).await.is_ok(),
+        _ => false
+    }
+}
+
+/// Moves a registered carrier to [DeviceState::Draining] and asks the agent to unregister it
+async fn drain(device: &mut ManagedDevice<'_>, context: &DaemonContext) {
+    let Some(mountpoint) = device.mountpoint().map(Path::to_path_buf) else {
+        return;
+    };
+
+    device.transition(DeviceState::Draining);
+    context.events.emit(CarrierEvent::Disconnected { device: device.id().to_owned(), mountpoint: mountpoint.clone() });
+
+    if context.agent.send(AgentMessage::Unregister(mountpoint)).await.is_err() {
+        fail(device, DeviceError::AgentStopped, context);
+    }
+}
+
+/// Re-evaluates devices against the device filters of a reloaded configuration
+///
+/// Newly denied devices are unregistered and released, newly allowed ones are handled.
+/// Carriers still allowed are left untouched, a new contact duration or strategy applies to next registrations.
+async fn apply_device_filters<'a>(manager: &'a DiskManager<'a>, devices: &mut HashMap<OwnedObjectPath, ManagedDevice<'a>>, previous: &Config, config: &Config, context: &DaemonContext) {
+    let denied: Vec<OwnedObjectPath> = devices.values()
+        .filter(|device| !config.devices.allows(device.identity()))
+        .map(|device| device.path().clone())
+        .collect();
+
+    for path in denied.iter() {
+        if let Some(device) = devices.get_mut(path) {
+            println!("Device {} is now filtered out by configuration", device.id());
+            withdraw(device, context).await;
+        }
+    }
+
+    if !denied.is_empty() {
+        // Carriers must stay mounted until the agent unregistered them
+        wait_for_agent(context).await;
+    }
+
+    for path in denied {
+        if let Some(mut device) = devices.remove(&path) {
+            if let Err(e) = device.release().await {
+                fail(&mut device, format!("failed to unmount: {e}"), context);
+            }
+        }
+    }
+
+    let block_devices = match manager.block_devices().await {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("Failed to list block devices: {e}");
+            return;
+        }
+    };
+
+    for block_device in block_devices {
+        if devices.contains_key(block_device.path()) {
             continue;
         }
 
-        let device_id = device.id().await.unwrap();
+        let Ok(identity) = block_device.identity().await else {
+            continue;
+        };
+        if previous.devices.allows(&identity) || !config.devices.allows(&identity) {
+            continue;
+        }
 
-        match mount_and_register(device, agent_message_sender.clone(), cli.as_user.clone()).await {
+        let device_path = block_device.path().clone();
+        let (device, identity) = match removable_mountable(block_device, config).await {
             Err(e) => {
-                eprintln!("Failed to mount and register device {}: {e}", device_id);
+                eprintln!("Failed to inspect device {}: {e}", device_path.as_str());
                 continue;
             },
-            Ok(()) => {
-
-            }
+            Ok(None) => continue,
+            Ok(Some(d)) => d
         };
 
-
+        let device = handle_device(device, identity, context, config).await;
+        if device.state() != DeviceState::Ejected {
+            devices.insert(device_path, device);
+        }
     }
+}
 
-    agent_message_sender.send(AgentMessage::Shutdown).await.unwrap();
-    agent_task.await;
+/// Forwards termination and reload signals to the returned channel
+fn watch_signals() -> io::Result<Receiver<i32>> {
+    let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP])?;
+    let (sender, receiver) = channel::unbounded();
+
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if sender.send_blocking(signal).is_err() {
+                return;
+            }
+        }
+    });
+
+    Ok(receiver)
+}
+
+/// Events watched by the daemon
+enum DaemonEvent<'a> {
+    Added(BlockDevice<'a>),
+    Removed(OwnedObjectPath),
+    Signal(i32),
+    Request(ServiceRequest),
+    Registration(RegistrationOutcome),
+    /// Time to check the activity of registered carriers
+    Tick,
+    /// Time to tell the systemd watchdog the daemon is alive
+    Watchdog
 }
 
-async fn init_all_devices<'a>(manager: &'a DiskManager<'a>, agent_message_sender: Sender<AgentMessage>, mount_as_user: Option<String>) -> Result<(), zbus::Error> {
+/// Handles every device already plugged in
+///
+/// Returns the devices still followed by the daemon
+async fn init_all_devices<'a>(manager: &'a DiskManager<'a>, context: &DaemonContext, config: &Config) -> Result<HashMap<OwnedObjectPath, ManagedDevice<'a>>, zbus::Error> {
 
     let devices = manager.block_devices().await?;
 
-    let mountable_removable_drive = try_join_all(
+    let handled = join_all(
         devices.into_iter()
             .map(async |device| {
-                let Some(drive) = device.drive().await? else {
-                    return Ok(None)
-                };
-
-                if !drive.removable().await? {
-                    return Ok(None)
-                }
+                let device_path = device.path().clone();
 
-                match device.try_into_mountable().await {
-                    Ok(d) => Ok(Some(d)),
-                    Err(IntoMountableDeviceError::NotMountable) => Ok(None),
-                    Err(IntoMountableDeviceError::Dbus(e)) => Err(e)
+                match removable_mountable(device, config).await {
+                    Err(e) => {
+                        eprintln!("Failed to inspect device {}: {e}", device_path.as_str());
+                        None
+                    },
+                    Ok(None) => None,
+                    Ok(Some((device, identity))) => Some(handle_device(device, identity, context, config).await)
                 }
             })
-        ).await?.into_iter().filter_map(|it| it);
+    ).await;
 
-    try_join_all(
-        mountable_removable_drive.map(async |device| {
+    Ok(handled.into_iter()
+        .flatten()
+        .filter(|device| device.state() != DeviceState::Ejected)
+        .map(|device| (device.path().clone(), device))
+        .collect())
+}
 
-            let (mounted_by_us, mountpoint) = match device.mountpoints().await?.into_iter().next() {
-                Some(m) => (false, m),
-                None => (true, device.mount().await?)
-            };
+/// Returns the device as a [MountableDevice] with its [DeviceIdentity] if it is a mountable filesystem on a removable drive,
+/// allowed by the configuration
+async fn removable_mountable<'a>(device: BlockDevice<'a>, config: &Config) -> Result<Option<(MountableDevice<'a>, DeviceIdentity)>, zbus::Error> {
+    let Some(drive) = device.drive().await? else {
+        return Ok(None)
+    };
 
-            match FileCarrierHierarchy::folder_is_file_carrier(&mountpoint) {
-                Ok(is_fc) => {
-                    if is_fc {
-                        device.unmount().await?;
-                        mount_and_register(device, agent_message_sender.clone(), mount_as_user.clone()).await?;
-                        return Ok(());
-                    }
-                },
-                Err(e) => eprintln!("Failed to open folder {}: {}", mountpoint.display(), e)
-            }
-            
-            println!("Device {} is not a file carrier", mountpoint.display());
-                
-            if mounted_by_us {
-                device.unmount().await?;
-            }
+    if !drive.removable().await? {
+        return Ok(None)
+    }
 
-            Ok::<_, zbus::Error>(())
-        })
-    ).await?;
+    let identity = device.identity().await?;
+    if !config.devices.allows(&identity) {
+        println!("Device {} is filtered out by configuration", identity.id);
+        return Ok(None)
+    }
 
-    Ok(())
+    match device.try_into_mountable().await {
+        Ok(d) => Ok(Some((d, identity))),
+        Err(IntoMountableDeviceError::NotMountable) => Ok(None),
+        Err(IntoMountableDeviceError::Dbus(e)) => Err(e)
+    }
 }
 
-async fn mount_and_register<'a>(device: MountableDevice<'a>, agent_message_sender: Sender<AgentMessage>, mount_as_user: Option<String>) -> Result<(), zbus::Error> {
-    println!("Should mount and register filecarrier in {:?}", device);
+/// Brings a device as far as possible in its lifecycle : mounted, validated then registered if it is a file carrier
+///
+/// Failures only affect this device, which is returned in [DeviceState::Failed].
+async fn handle_device<'a>(device: MountableDevice<'a>, identity: DeviceIdentity, context: &DaemonContext, config: &Config) -> ManagedDevice<'a> {
+    let mut device = ManagedDevice::detect(device, identity);
 
-    let mountpoint = match mount_as_user {
-        Some(username) => device.mount_as_user(&username).await?,
-        None => device.mount().await?
-    };
+    if let Err(e) = mount_and_register(&mut device, context, config).await {
+        fail(&mut device, e, context);
+    }
 
-    match FileCarrierHierarchy::folder_is_file_carrier(&mountpoint) {
-        Ok(is_fc) => {
-            if !is_fc {
-                device.unmount().await?;
-                println!("Device {} is not a file carrier", device.id().await.unwrap());
-                return Ok(());
-            }
-        },
-        Err(e) => eprintln!("Failed to open folder {}: {}", mountpoint.display(), e)
+    device
+}
+
+/// Mounts a device and registers it if it is a file carrier, releasing it otherwise
+///
+/// Drives whose label matches the configured pattern are initialized as file carriers first.
+async fn mount_and_register(device: &mut ManagedDevice<'_>, context: &DaemonContext, config: &Config) -> Result<(), DeviceError> {
+    device.mount(&config.mount).await?;
+
+    if !device.validate()? {
+        if !config.daemon.auto_initializes(&device.identity().label) {
+            return release_non_carrier(device, context).await;
+        }
+
+        // The hierarchy is created with the same owner as carriers registered later
+        device.ensure_writable(&config.mount)?;
+        device.initialize()?;
+
+        if !device.validate()? {
+            return release_non_carrier(device, context).await;
+        }
     }
 
-    agent_message_sender.send(AgentMessage::Register(mountpoint)).await
-        .expect("Failed to send agent message");
+    device.ensure_writable(&config.mount)?;
 
+    // Carriers used for the first time are connected too, even if they are never registered
+    let mountpoint = device.mountpoint().map(Path::to_path_buf).unwrap_or_default();
+    context.events.emit(CarrierEvent::Connected { device: device.id().to_owned(), mountpoint });
+
+    register(device, context).await
+}
+
+/// Releases a drive which is not a file carrier
+async fn release_non_carrier(device: &mut ManagedDevice<'_>, context: &DaemonContext) -> Result<(), DeviceError> {
+    let mountpoint = device.mountpoint().map(Path::to_path_buf).unwrap_or_default();
+    device.release().await?;
+    context.events.emit(CarrierEvent::NotACarrier { device: device.id().to_owned(), mountpoint });
     Ok(())
 }
 
+/// Hands a validated carrier to the agent for registration
+///
+/// The carrier stays [DeviceState::Validated] until the agent reports a [RegistrationOutcome].
+async fn register(device: &mut ManagedDevice<'_>, context: &DaemonContext) -> Result<(), DeviceError> {
+    let Some(mountpoint) = device.mountpoint().map(Path::to_path_buf) else {
+        return Ok(());
+    };
+
+    context.agent.send(AgentMessage::Register(mountpoint)).await
+        .map_err(|_| DeviceError::AgentStopped)
+}
+
 enum AgentMessage {
     Register(PathBuf),
+    /// Unregisters a file carrier, even if it was already unplugged
+    Unregister(PathBuf),
+    /// Replaces the configuration used for next registrations
+    Reconfigure(Box<Config>),
+    /// Answers once every previous message was handled
+    Barrier(Sender<()>),
     Shutdown
 }
 
-async fn agent_task<T:AapStream>(receiver: Receiver<AgentMessage>, mut agent: RegisteredAgent<T>) {
+/// Result of a registration, reported by the agent task to the main loop
+#[derive(Debug, PartialEq, Eq)]
+enum RegistrationOutcome {
+    /// Contacts of the carrier were announced to the node
+    Registered {
+        mountpoint: PathBuf,
+        contact_eids: Vec<String>,
+        reaches: Vec<String>
+    },
+    /// The carrier is used for the first time, it has no contact to announce
+    FirstUse(PathBuf),
+    Failed {
+        mountpoint: PathBuf,
+        error: String
+    }
+}
+
+/// Registers and unregisters carriers with the config agent, reconnecting to the core when it restarts
+///
+/// Registrations are reported to `outcomes` once done, carriers waiting for the core are reported when it is back.
+async fn agent_task(receiver: Receiver<AgentMessage>, config: Config, outcomes: Sender<RegistrationOutcome>) {
+    let mut state = AgentState {
+        core: CoreConnection::new(config.socket.clone()),
+        config,
+        outcomes,
+        active: HashMap::new(),
+        pending: Vec::new(),
+        deletions: Vec::new()
+    };
+
     loop {
-        match receiver.recv().await {
-            Ok(AgentMessage::Register(path)) => {
-                if let Err(e) = register_folder(
-                    &mut agent, &path, Duration::from_secs(10 * 25 * 3600)) {
-                    eprintln!("Failed to register folder {}: {}", path.display(), e)
-                } else {
-                    println!("Registered folder {} as file carrier", path.display());
-                }
+        state.reconnect();
+
+        let message = match future::timeout(state.core.next_check(), receiver.recv()).await {
+            Err(_) => continue, // Time to check the connection
+            Ok(m) => m
+        };
+
+        match message {
+            Ok(AgentMessage::Register(path)) => state.register(path),
+            Ok(AgentMessage::Unregister(path)) => state.unregister(path),
+            Ok(AgentMessage::Reconfigure(new_config)) => {
+                state.core.set_socket(new_config.socket.clone());
+                state.config = *new_config;
+            },
+            Ok(AgentMessage::Barrier(sender)) => {
+                let _ = sender.send(()).await;
             },
             Ok(AgentMessage::Shutdown) => return,
             Err(RecvError) => return // Empty and closed channel (end of task)
@@ -178,16 +720,385 @@ async fn agent_task<T:AapStream>(receiver: Receiver<AgentMessage>, mut agent: Re
     }
 }
 
+/// State of the agent task
+struct AgentState {
+    core: CoreConnection,
+    config: Config,
+    outcomes: Sender<RegistrationOutcome>,
+    /// Registrations of connected file carriers, needed to delete contacts of unplugged ones and to announce them again
+    /// when the core restarts
+    active: HashMap<PathBuf, RegistrationReport>,
+    /// Carriers to register once the core is reachable
+    pending: Vec<PathBuf>,
+    /// Contacts of unregistered carriers to delete once the core is reachable
+    deletions: Vec<String>
+}
+
+impl AgentState {
+    fn register(&mut self, path: PathBuf) {
+        if self.active.contains_key(&path) || self.pending.contains(&path) {
+            return;
+        }
+
+        let Some(agent) = self.core.agent() else {
+            println!("Archipel Core is unreachable, folder {} will be registered once it is back", path.display());
+            self.pending.push(path);
+            return;
+        };
+
+        match register_folder(agent, &path, self.config.daemon.contact_duration(), self.config.strategy) {
+            Err(FileCarrierError::Ud3tnError(e)) => {
+                eprintln!("Connection to Archipel Core lost while registering folder {}: {e}", path.display());
+                self.core.disconnect();
+                self.pending.push(path);
+            },
+            Err(e) => {
+                eprintln!("Failed to register folder {}: {}", path.display(), e);
+                self.report(RegistrationOutcome::Failed { mountpoint: path, error: e.to_string() });
+            },
+            Ok(report) => {
+                for warning in report.warnings.iter() {
+                    eprintln!("Warning for folder {}: {}", path.display(), warning);
+                }
+                if report.first_use {
+                    println!("Folder {} is used for the first time, no contact to announce", path.display());
+                    self.report(RegistrationOutcome::FirstUse(path));
+                } else {
+                    println!("Registered folder {} as file carrier, reaches are: {}", path.display(), report.reaches_eid().join(";"));
+                    self.report(RegistrationOutcome::Registered {
+                        mountpoint: path.clone(),
+                        contact_eids: report.contact_eids(),
+                        reaches: report.reaches_eid()
+                    });
+                    self.active.insert(path, report);
+                }
+            }
+        }
+    }
+
+    fn report(&self, outcome: RegistrationOutcome) {
+        // The channel is unbounded, sending only fails once the main loop stopped
+        let _ = self.outcomes.try_send(outcome);
+    }
+
+    fn unregister(&mut self, path: PathBuf) {
+        self.pending.retain(|pending| pending != &path);
+
+        let Some(report) = self.active.remove(&path) else {
+            return;
+        };
+
+        let Some(agent) = self.core.agent() else {
+            println!("Archipel Core is unreachable, contacts of folder {} will be deleted once it is back", path.display());
+            self.deletions.extend(report.contact_eids());
+            return;
+        };
+
+        // An unplugged carrier can't be updated, only its contacts are deleted
+        let result = unregister_folder(agent, &path)
+            .map(|_| ())
+            .or_else(|_| delete_contacts(agent, &report.contact_eids()));
+
+        match result {
+            Err(e) => {
+                eprintln!("Failed to unregister folder {}: {}", path.display(), e);
+                if let FileCarrierError::Ud3tnError(_) = e {
+                    self.core.disconnect();
+                    self.deletions.extend(report.contact_eids());
+                }
+            },
+            Ok(()) => println!("Unregistered folder {}", path.display())
+        }
+    }
+
+    /// Connects to the core if needed, then deletes the contacts of carriers unregistered meanwhile, announces again
+    /// the contacts of registered carriers and registers pending ones, as a new core knows none of them
+    fn reconnect(&mut self) {
+        if !self.core.check() {
+            return;
+        }
+
+        let Some(agent) = self.core.agent() else {
+            return;
+        };
+
+        // The core may not have restarted, deleting contacts it does not know is harmless
+        if !self.deletions.is_empty() {
+            if let Err(e) = delete_contacts(agent, &self.deletions) {
+                eprintln!("Failed to delete contacts of unregistered carriers: {e}");
+                self.core.disconnect();
+                return;
+            }
+            println!("Deleted {} contact(s) of unregistered carriers", self.deletions.len());
+            self.deletions.clear();
+        }
+
+        let now = SystemTime::now();
+        for report in self.active.values_mut() {
+            // Windows announced at registration may already be over
+            report.renew_windows(now, self.config.daemon.contact_duration());
+            for contact in report.contacts.iter().chain(report.predicted.iter()) {
+                if let Err(e) = announce_contact(agent, &report.cla_address, contact, report.data_rate) {
+                    eprintln!("Failed to announce again contacts of folder {}: {e}", report.folder.display());
+                    self.core.disconnect();
+                    return;
+                }
+            }
+        }
+
+        if !self.active.is_empty() {
+            println!("Announced again contacts of {} file carrier(s)", self.active.len());
+        }
+
+        for path in mem::take(&mut self.pending) {
+            self.register(path);
+        }
+    }
+}
+
 #[derive(Debug, Parser)]
 struct Cli {
+    /// Configuration file to use instead of /etc/archipel-fc/config.toml and the user configuration
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// Socket of Archipel Core. Overrides the configured one
+    #[arg(short, long)]
+    socket: Option<PathBuf>,
     /// Mount file carrier for the provided user instead of user currently running daemon
-    /// Useful if Archipel Core is not running as current user
+    /// Useful if Archipel Core is not running as current user. Overrides the configured one
     #[arg(long)]
-    as_user: Option<String>
+    as_user: Option<String>,
+    /// How nodes reached by file carriers are announced: last-node, all-nodes or top-<N> most visited nodes. Overrides the configured one
+    #[arg(long)]
+    strategy: Option<ContactStrategy>
+}
+
+impl Cli {
+    /// Loads the configuration, with command line arguments taking precedence
+    fn load_config(&self) -> Result<Config, FileCarrierError> {
+        let mut config = Config::load(self.config.as_deref())?;
+        if let Some(socket) = self.socket.clone() {
+            config.socket = socket;
+        }
+        if let Some(as_user) = self.as_user.clone() {
+            config.mount.as_user = Some(as_user);
+        }
+        if let Some(strategy) = self.strategy {
+            config.strategy = strategy;
+        }
+        Ok(config)
+    }
 }
 
 fn main() {
     let cli = Cli::parse();
 
-    task::block_on(async_main(cli))
+    let config = match cli.load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Failed to load configuration: {e}");
+            process::exit(1);
+        }
+    };
+
+    task::block_on(async_main(cli, config))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+    use async_std::{channel, task};
+    use file_carrier::{config::Config, hierarchy::FileCarrierHierarchy, init::initialize_file_carrier, strategy::ContactStrategy, testing::{FakeAapServer, RecordedConfig, TempFolder}};
+
+    use crate::{agent_task, AgentMessage, RegistrationOutcome};
+
+    #[test]
+    fn agent_task_registers_folders() {
+        let server = FakeAapServer::start("dtn://current/").unwrap();
+        let folder = TempFolder::new("fcd-agent").unwrap();
+        initialize_file_carrier(folder.path()).unwrap();
+        fs::write(FileCarrierHierarchy::new(folder.path()).reaches_file(), "dtn://previous/").unwrap();
+
+        let (sender, receiver) = channel::unbounded();
+        let (outcome_sender, outcomes) = channel::unbounded();
+        let config = Config { socket: server.socket_path().to_path_buf(), ..Config::default() };
+
+        task::block_on(async {
+            let task = task::spawn(agent_task(receiver, config, outcome_sender));
+            sender.send(AgentMessage::Register(folder.path().to_path_buf())).await.unwrap();
+            // Already being registered
+            sender.send(AgentMessage::Register(folder.path().to_path_buf())).await.unwrap();
+            sender.send(AgentMessage::Shutdown).await.unwrap();
+            task.await;
+        });
+
+        let configs = server.configs();
+        assert_eq!(configs.len(), 1);
+        let RecordedConfig::AddContact { eid, reaches_eid, .. } = &configs[0] else {
+            panic!("Expected AddContact, got {:?}", configs[0]);
+        };
+        assert_eq!(reaches_eid, &vec!["dtn://previous/".to_owned()]);
+
+        assert_eq!(outcomes.try_recv(), Ok(RegistrationOutcome::Registered {
+            mountpoint: folder.path().to_path_buf(),
+            contact_eids: vec![eid.clone()],
+            reaches: reaches_eid.clone()
+        }));
+        assert!(outcomes.try_recv().is_err());
+    }
+
+    #[test]
+    fn agent_task_unregisters_unplugged_folders() {
+        let server = FakeAapServer::start("dtn://current/").unwrap();
+        let folder = TempFolder::new("fcd-agent").unwrap();
+        initialize_file_carrier(folder.path()).unwrap();
+        fs::write(FileCarrierHierarchy::new(folder.path()).reaches_file(), "dtn://previous/").unwrap();
+
+        let (sender, receiver) = channel::unbounded();
+        let (outcome_sender, _) = channel::unbounded();
+        let config = Config { socket: server.socket_path().to_path_buf(), ..Config::default() };
+
+        task::block_on(async {
+            let task = task::spawn(agent_task(receiver, config, outcome_sender));
+            sender.send(AgentMessage::Register(folder.path().to_path_buf())).await.unwrap();
+
+            // The carrier is unplugged once the agent is done writing to it
+            let (barrier, reached) = channel::bounded(1);
+            sender.send(AgentMessage::Barrier(barrier)).await.unwrap();
+            reached.recv().await.unwrap();
+
+            fs::remove_dir_all(folder.path()).unwrap();
+            sender.send(AgentMessage::Unregister(folder.path().to_path_buf())).await.unwrap();
+            sender.send(AgentMessage::Shutdown).await.unwrap();
+            task.await;
+        });
+
+        let configs = server.configs();
+        let RecordedConfig::AddContact { eid, .. } = &configs[0] else {
+            panic!("Expected AddContact, got {:?}", configs[0]);
+        };
+        assert_eq!(configs[1], RecordedConfig::DeleteContact(eid.clone()));
+    }
+
+    #[test]
+    fn agent_task_deletes_contacts_once_core_is_back() {
+        let server = FakeAapServer::start("dtn://current/").unwrap();
+        let folder = TempFolder::new("fcd-agent").unwrap();
+        initialize_file_carrier(folder.path()).unwrap();
+        fs::write(FileCarrierHierarchy::new(folder.path()).reaches_file(), "dtn://previous/").unwrap();
+
+        let (sender, receiver) = channel::unbounded();
+        let (outcome_sender, _) = channel::unbounded();
+        let config = Config { socket: server.socket_path().to_path_buf(), ..Config::default() };
+
+        task::block_on(async {
+            let task = task::spawn(agent_task(receiver, config, outcome_sender));
+            sender.send(AgentMessage::Register(folder.path().to_path_buf())).await.unwrap();
+
+            while server.configs().is_empty() {
+                task::sleep(Duration::from_millis(10)).await;
+            }
+            let RecordedConfig::AddContact { eid, .. } = server.configs().remove(0) else {
+                panic!("Expected AddContact");
+            };
+
+            // Unregistering fails, contacts are deleted once the agent reconnected
+            server.clear();
+            server.disconnect_all();
+            sender.send(AgentMessage::Unregister(folder.path().to_path_buf())).await.unwrap();
+
+            while server.configs().is_empty() {
+                task::sleep(Duration::from_millis(10)).await;
+            }
+            assert_eq!(server.configs(), vec![RecordedConfig::DeleteContact(eid)]);
+
+            sender.send(AgentMessage::Shutdown).await.unwrap();
+            task.await;
+        });
+    }
+
+    #[test]
+    fn agent_task_applies_new_configuration() {
+        let server = FakeAapServer::start("dtn://current/").unwrap();
+        let folder = TempFolder::new("fcd-agent").unwrap();
+        initialize_file_carrier(folder.path()).unwrap();
+        fs::write(FileCarrierHierarchy::new(folder.path()).reaches_file(), "dtn://a/\ndtn://b/").unwrap();
+
+        let (sender, receiver) = channel::unbounded();
+        let (outcome_sender, _) = channel::unbounded();
+        let config = Config { socket: server.socket_path().to_path_buf(), ..Config::default() };
+        let new_config = Config { strategy: ContactStrategy::AllNodes, ..config.clone() };
+
+        task::block_on(async {
+            let task = task::spawn(agent_task(receiver, config, outcome_sender));
+            sender.send(AgentMessage::Reconfigure(Box::new(new_config))).await.unwrap();
+            sender.send(AgentMessage::Register(folder.path().to_path_buf())).await.unwrap();
+
+            let (barrier, reached) = channel::bounded(1);
+            sender.send(AgentMessage::Barrier(barrier)).await.unwrap();
+            reached.recv().await.unwrap();
+            assert_eq!(server.configs().len(), 2);
+
+            sender.send(AgentMessage::Shutdown).await.unwrap();
+            task.await;
+        });
+    }
+
+    #[test]
+    fn agent_task_announces_contacts_again_after_core_restart() {
+        let server = FakeAapServer::start("dtn://current/").unwrap();
+        let folders = [TempFolder::new("fcd-agent").unwrap(), TempFolder::new("fcd-agent").unwrap()];
+        for folder in folders.iter() {
+            initialize_file_carrier(folder.path()).unwrap();
+            fs::write(FileCarrierHierarchy::new(folder.path()).reaches_file(), "dtn://previous/").unwrap();
+        }
+
+        let (sender, receiver) = channel::unbounded();
+        let (outcome_sender, _) = channel::unbounded();
+        let config = Config { socket: server.socket_path().to_path_buf(), ..Config::default() };
+
+        let (replayed_eid, restarted) = task::block_on(async {
+            let task = task::spawn(agent_task(receiver, config, outcome_sender));
+            sender.send(AgentMessage::Register(folders[0].path().to_path_buf())).await.unwrap();
+
+            while server.configs().is_empty() {
+                task::sleep(Duration::from_millis(10)).await;
+            }
+            let RecordedConfig::AddContact { eid: replayed_eid, .. } = server.configs().remove(0) else {
+                panic!("Expected AddContact");
+            };
+
+            // Windows are announced with a precision of one second
+            task::sleep(Duration::from_millis(1100)).await;
+            let restarted = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+            // The next registration fails, the agent reconnects and announces both carriers
+            server.clear();
+            server.disconnect_all();
+            sender.send(AgentMessage::Register(folders[1].path().to_path_buf())).await.unwrap();
+
+            while server.configs().len() < 2 {
+                task::sleep(Duration::from_millis(10)).await;
+            }
+
+            sender.send(AgentMessage::Shutdown).await.unwrap();
+            task.await;
+            (replayed_eid, restarted)
+        });
+
+        let configs = server.configs();
+        assert_eq!(configs.len(), 2);
+        assert!(configs.iter().all(|c| matches!(c, RecordedConfig::AddContact { .. })));
+
+        // The current window of the replayed contact starts after the restart, not at the first registration
+        let replayed = configs.iter()
+            .find_map(|c| match c {
+                RecordedConfig::AddContact { eid, contacts, .. } if eid == &replayed_eid => Some(contacts[0].clone()),
+                _ => None
+            })
+            .unwrap();
+        let start: u64 = replayed.split(',').next().unwrap().parse().unwrap();
+        assert!(start >= restarted);
+    }
 }
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+
+use async_std::channel::Receiver;
+use file_carrier::config::NotificationConfig;
+use futures::{stream, StreamExt};
+use zbus::{zvariant::Value, Connection};
+
+use crate::events::CarrierEvent;
+
+const APP_NAME: &str = "Archipel File Carrier";
+const APP_ICON: &str = "drive-removable-media";
+
+/// Urgency levels of the notification specification
+const URGENCY_NORMAL: u8 = 1;
+const URGENCY_CRITICAL: u8 = 2;
+
+/// Content of a desktop notification
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    pub summary: String,
+    pub body: String,
+    pub urgency: u8
+}
+
+impl Notification {
+    /// Returns the notification to show for an event, if any is enabled for its kind
+    pub fn for_event(event: &CarrierEvent, config: &NotificationConfig) -> Option<Self> {
+        let (summary, body, urgency) = match event {
+            CarrierEvent::Connected { mountpoint, .. } if config.connected =>
+                ("File carrier connected", format!("{} was mounted", mountpoint.display()), URGENCY_NORMAL),
+            CarrierEvent::Registered { mountpoint, reaches, .. } if config.registered => {
+                let body = match reaches.is_empty() {
+                    true => format!("{} announces no node", mountpoint.display()),
+                    false => format!("{} reaches {}", mountpoint.display(), reaches.join(", "))
+                };
+                ("File carrier registered", body, URGENCY_NORMAL)
+            },
+            CarrierEvent::NotACarrier { mountpoint, .. } if config.not_a_carrier =>
+                ("Not a file carrier", format!("{} was released", mountpoint.display()), URGENCY_NORMAL),
+            CarrierEvent::SafeToRemove { mountpoint, .. } if config.safe_to_remove =>
+                ("File carrier can be removed", format!("{} was ejected", mountpoint.display()), URGENCY_NORMAL),
+            CarrierEvent::Failed { device, error } if config.errors =>
+                ("File carrier error", format!("{device}: {error}"), URGENCY_CRITICAL),
+            _ => return None
+        };
+
+        Some(Self { summary: summary.to_owned(), body, urgency })
+    }
+}
+
+enum NotifierMessage {
+    Event(CarrierEvent),
+    Reconfigure(NotificationConfig)
+}
+
+/// Shows a desktop notification for every enabled event, until `events` is closed
+///
+/// Notifications are sent through `org.freedesktop.Notifications` on the session bus `connection`.
+pub async fn notify(connection: Connection, events: Receiver<CarrierEvent>, configs: Receiver<NotificationConfig>, mut config: NotificationConfig) {
+    let proxy = match notifications::NotificationsProxy::new(&connection).await {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("Desktop notifications disabled: {e}");
+            return;
+        }
+    };
+
+    let mut messages = stream::select(
+        events.map(NotifierMessage::Event),
+        configs.map(NotifierMessage::Reconfigure)
+    );
+
+    while let Some(message) = messages.next().await {
+        let notification = match message {
+            NotifierMessage::Reconfigure(new_config) => {
+                config = new_config;
+                continue;
+            },
+            NotifierMessage::Event(event) => match Notification::for_event(&event, &config) {
+                Some(n) => n,
+                None => continue
+            }
+        };
+
+        let hints = HashMap::from([("urgency", Value::from(notification.urgency))]);
+        if let Err(e) = proxy.notify(APP_NAME, 0, APP_ICON, &notification.summary, &notification.body, &[], &hints, -1).await {
+            eprintln!("Failed to show desktop notification: {e}");
+        }
+    }
+}
+
+mod notifications {
+    use std::collections::HashMap;
+    use zbus::{proxy, zvariant, Result};
+
+    #[proxy(
+        interface = "org.freedesktop.Notifications",
+        default_service = "org.freedesktop.Notifications",
+        default_path = "/org/freedesktop/Notifications"
+    )]
+    pub trait Notifications {
+        #[allow(clippy::too_many_arguments)]
+        async fn notify(
+            &self,
+            app_name: &str,
+            replaces_id: u32,
+            app_icon: &str,
+            summary: &str,
+            body: &str,
+            actions: &[&str],
+            hints: &HashMap<&str, zvariant::Value<'_>>,
+            expire_timeout: i32,
+        ) -> Result<u32>;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use file_carrier::config::NotificationConfig;
+
+    use crate::{events::CarrierEvent, notify::Notification};
+
+    #[test]
+    fn notifications_per_event() {
+        let registered = CarrierEvent::Registered {
+            device: "by-uuid-1".to_owned(),
+            mountpoint: PathBuf::from("/media/carrier"),
+            contact_eids: vec!["dtn://file-carrier-2b1c/".to_owned()],
+            reaches: vec!["dtn://node-a/".to_owned(), "dtn://node-b/".to_owned()]
+        };
+        let failed = CarrierEvent::Failed { device: "by-uuid-1".to_owned(), error: "failed to eject".to_owned() };
+        let connected = CarrierEvent::Connected { device: "by-uuid-1".to_owned(), mountpoint: PathBuf::from("/media/carrier") };
+
+        let config = NotificationConfig::default();
+        let notification = Notification::for_event(&registered, &config).unwrap();
+        assert_eq!(notification.summary, "File carrier registered");
+        assert_eq!(notification.body, "/media/carrier reaches dtn://node-a/, dtn://node-b/");
+        assert_eq!(Notification::for_event(&failed, &config).unwrap().urgency, 2);
+        assert_eq!(Notification::for_event(&connected, &config).unwrap().body, "/media/carrier was mounted");
+
+        let config = NotificationConfig { connected: false, registered: false, ..NotificationConfig::default() };
+        assert_eq!(Notification::for_event(&registered, &config), None);
+        assert_eq!(Notification::for_event(&connected, &config), None);
+        assert!(Notification::for_event(&failed, &config).is_some());
+    }
+}
@@ -0,0 +1,122 @@
+use std::path::PathBuf;
+
+use async_std::{channel::{self, Receiver, Sender}, task};
+use zbus::{fdo, interface, object_server::SignalEmitter, Connection};
+
+use crate::events::CarrierEvent;
+
+/// Bus name owned by the daemon
+pub const SERVICE_NAME: &str = "org.archipel.FileCarrier1";
+
+/// Object exposing the [FileCarrierService]
+pub const SERVICE_PATH: &str = "/org/archipel/FileCarrier1";
+
+/// State of a carrier, as exposed on D-Bus
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CarrierStatus {
+    pub mountpoint: PathBuf,
+    /// Block device id
+    pub device: String,
+    /// Lifecycle state of the device
+    pub state: String,
+    /// EIDs of the contacts currently announced for the carrier
+    pub contact_eids: Vec<String>
+}
+
+/// Requests from D-Bus clients, handled by the daemon main loop which owns the devices
+pub enum ServiceRequest {
+    ListCarriers(Sender<Vec<CarrierStatus>>),
+    Register(PathBuf, Sender<Result<(), String>>),
+    Unregister(PathBuf, Sender<Result<(), String>>),
+    Eject(PathBuf, Sender<Result<(), String>>)
+}
+
+/// The `org.archipel.FileCarrier1` interface, carriers are identified by their mountpoint
+pub struct FileCarrierService {
+    requests: Sender<ServiceRequest>
+}
+
+impl FileCarrierService {
+    async fn call(&self, request: impl FnOnce(Sender<Result<(), String>>) -> ServiceRequest) -> fdo::Result<()> {
+        let (sender, receiver) = channel::bounded(1);
+        self.requests.send(request(sender)).await
+            .map_err(|_| fdo::Error::Failed("daemon is stopping".to_owned()))?;
+
+        receiver.recv().await
+            .map_err(|_| fdo::Error::Failed("daemon is stopping".to_owned()))?
+            .map_err(fdo::Error::Failed)
+    }
+}
+
+#[interface(name = "org.archipel.FileCarrier1")]
+impl FileCarrierService {
+    /// Registers a mounted carrier which was unregistered
+    async fn register(&self, mountpoint: &str) -> fdo::Result<()> {
+        self.call(|reply| ServiceRequest::Register(PathBuf::from(mountpoint), reply)).await
+    }
+
+    /// Unregisters a carrier, leaving it mounted
+    async fn unregister(&self, mountpoint: &str) -> fdo::Result<()> {
+        self.call(|reply| ServiceRequest::Unregister(PathBuf::from(mountpoint), reply)).await
+    }
+
+    /// Unregisters a carrier, unmounts it and powers its drive off
+    async fn eject(&self, mountpoint: &str) -> fdo::Result<()> {
+        self.call(|reply| ServiceRequest::Eject(PathBuf::from(mountpoint), reply)).await
+    }
+
+    /// Carriers followed by the daemon as (mountpoint, device, state, contact EIDs)
+    #[zbus(property)]
+    async fn carriers(&self) -> fdo::Result<Vec<(String, String, String, Vec<String>)>> {
+        let (sender, receiver) = channel::bounded(1);
+        self.requests.send(ServiceRequest::ListCarriers(sender)).await
+            .map_err(|_| fdo::Error::Failed("daemon is stopping".to_owned()))?;
+
+        let carriers = receiver.recv().await
+            .map_err(|_| fdo::Error::Failed("daemon is stopping".to_owned()))?;
+
+        Ok(carriers.into_iter()
+            .map(|c| (c.mountpoint.to_string_lossy().into_owned(), c.device, c.state, c.contact_eids))
+            .collect())
+    }
+
+    #[zbus(signal)]
+    async fn carrier_connected(emitter: &SignalEmitter<'_>, mountpoint: &str, device: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn carrier_disconnected(emitter: &SignalEmitter<'_>, mountpoint: &str, device: &str) -> zbus::Result<()>;
+}
+
+/// Exposes the [FileCarrierService] on `connection` and owns [SERVICE_NAME]
+///
+/// Signals and property changes are sent for every event received from `events`.
+pub async fn serve(connection: &Connection, requests: Sender<ServiceRequest>, events: Receiver<CarrierEvent>) -> zbus::Result<()> {
+    connection.object_server().at(SERVICE_PATH, FileCarrierService { requests }).await?;
+    connection.request_name(SERVICE_NAME).await?;
+
+    let interface = connection.object_server().interface::<_, FileCarrierService>(SERVICE_PATH).await?;
+
+    task::spawn(async move {
+        while let Ok(event) = events.recv().await {
+            let emitter = interface.signal_emitter();
+            let result = match &event {
+                CarrierEvent::Connected { device, mountpoint } =>
+                    FileCarrierService::carrier_connected(emitter, &mountpoint.to_string_lossy(), device).await,
+                CarrierEvent::Disconnected { device, mountpoint } =>
+                    FileCarrierService::carrier_disconnected(emitter, &mountpoint.to_string_lossy(), device).await,
+                _ => Ok(())
+            };
+
+            let result = match result {
+                Ok(()) => interface.get().await.carriers_changed(emitter).await,
+                Err(e) => Err(e)
+            };
+
+            if let Err(e) = result {
+                eprintln!("Failed to signal event on D-Bus: {e}");
+            }
+        }
+    });
+
+    Ok(())
+}
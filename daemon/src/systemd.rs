@@ -0,0 +1,59 @@
+use std::{env, ffi::OsStr, io, os::{linux::net::SocketAddrExt, unix::{ffi::OsStrExt, net::{SocketAddr, UnixDatagram}}}, process, time::Duration};
+
+/// Sends a state such as `READY=1` to systemd, if the daemon was started by a `Type=notify` unit
+pub fn notify(state: &str) {
+    let Some(socket) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    if let Err(e) = send(&socket, state) {
+        eprintln!("Failed to notify systemd: {e}");
+    }
+}
+
+/// Interval in which systemd expects `WATCHDOG=1`, if the watchdog is enabled for the daemon
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Some(pid) = env::var_os("WATCHDOG_PID") {
+        if pid.to_str()?.parse::<u32>().ok()? != process::id() {
+            return None;
+        }
+    }
+
+    let usec = env::var_os("WATCHDOG_USEC")?.to_str()?.parse::<u64>().ok()?;
+    Some(Duration::from_micros(usec)).filter(|interval| !interval.is_zero())
+}
+
+/// Sends a state to a notification socket, abstract sockets are prefixed by `@`
+fn send(socket: &OsStr, state: &str) -> io::Result<()> {
+    let address = match socket.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(socket)?
+    };
+
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &address)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixDatagram;
+
+    use file_carrier::testing::TempFolder;
+
+    use crate::systemd::send;
+
+    #[test]
+    fn notification_socket() {
+        let folder = TempFolder::new("fcd-notify").unwrap();
+        let path = folder.path().join("notify.socket");
+        let socket = UnixDatagram::bind(&path).unwrap();
+
+        send(path.as_os_str(), "READY=1").unwrap();
+
+        let mut buffer = [0; 64];
+        let size = socket.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"READY=1");
+
+        assert!(send(folder.path().join("missing.socket").as_os_str(), "READY=1").is_err());
+    }
+}
@@ -0,0 +1,523 @@
+use std::{env, fs, io, path::{Path, PathBuf}, str::FromStr, time::Duration};
+
+use serde::{de, Deserialize, Deserializer};
+use toml::Table;
+
+use crate::{error::FileCarrierError, strategy::ContactStrategy};
+
+/// Configuration shared by every user of the host
+pub const SYSTEM_CONFIG: &str = "/etc/archipel-fc/config.toml";
+
+/// Configuration of `archipelfc` and `archipelfc-daemon`
+///
+/// Loaded from [SYSTEM_CONFIG] then from the user configuration, overriding it key by key.
+/// Every key is optional.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    /// Unix socket of Archipel Core
+    pub socket: PathBuf,
+    /// How nodes reached by file carriers are announced
+    #[serde(deserialize_with = "from_str")]
+    pub strategy: ContactStrategy,
+    pub cli: CliConfig,
+    pub daemon: DaemonConfig,
+    pub mount: MountConfig,
+    pub notifications: NotificationConfig,
+    pub hooks: HookConfig,
+    pub devices: DeviceFilters
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            socket: PathBuf::from("/run/archipel-core/archipel-core.socket"),
+            strategy: ContactStrategy::default(),
+            cli: CliConfig::default(),
+            daemon: DaemonConfig::default(),
+            mount: MountConfig::default(),
+            notifications: NotificationConfig::default(),
+            hooks: HookConfig::default(),
+            devices: DeviceFilters::default()
+        }
+    }
+}
+
+/// Settings of `archipelfc`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct CliConfig {
+    /// Duration of contacts announced by `archipelfc register`, in seconds
+    pub contact_duration: u64
+}
+
+impl Default for CliConfig {
+    fn default() -> Self {
+        Self { contact_duration: 300 }
+    }
+}
+
+impl CliConfig {
+    pub fn contact_duration(&self) -> Duration {
+        Duration::from_secs(self.contact_duration)
+    }
+}
+
+/// Settings of `archipelfc-daemon`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// Duration of contacts announced for plugged carriers, in seconds. Contacts are deleted when carriers are unplugged
+    pub contact_duration: u64,
+    /// Drives which are not file carriers and whose filesystem label matches this pattern are initialized as file carriers.
+    /// Disabled if not set
+    pub auto_init_label: Option<String>,
+    /// Registered carriers whose data folder did not change for this number of seconds are unregistered, unmounted
+    /// and powered off so they can be unplugged safely. Disabled if not set
+    pub eject_after: Option<u64>
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        Self { contact_duration: 10 * 25 * 3600, auto_init_label: None, eject_after: None }
+    }
+}
+
+impl DaemonConfig {
+    pub fn contact_duration(&self) -> Duration {
+        Duration::from_secs(self.contact_duration)
+    }
+
+    pub fn eject_after(&self) -> Option<Duration> {
+        self.eject_after.map(Duration::from_secs)
+    }
+
+    /// Returns `true` if a drive with the provided filesystem label has to be initialized as a file carrier
+    pub fn auto_initializes(&self, label: &str) -> bool {
+        self.auto_init_label.as_deref()
+            .is_some_and(|pattern| !label.is_empty() && glob_match(pattern, label))
+    }
+}
+
+/// How the daemon mounts removable drives
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct MountConfig {
+    /// Mount drives for this user instead of the user running the daemon
+    pub as_user: Option<String>,
+    /// Mount with `nosuid,nodev,noexec`, so nothing on a foreign drive can be run
+    pub hardened: bool,
+    /// Mount with `sync`, writes reach the drive right away at the cost of speed and flash wear
+    pub sync: bool,
+    /// Mount vfat filesystems with `flush`, writes reach the drive earlier
+    pub flush: bool,
+    /// Mount vfat and exfat filesystems with the `uid` and `gid` of the mounting user, as they don't store owners
+    pub owner_ids: bool,
+    /// Additional filesystem options passed to udisks2, such as `noatime`
+    pub options: Vec<String>
+}
+
+impl Default for MountConfig {
+    fn default() -> Self {
+        Self {
+            as_user: None,
+            hardened: true,
+            sync: false,
+            flush: false,
+            owner_ids: true,
+            options: Vec::new()
+        }
+    }
+}
+
+impl MountConfig {
+    /// Filesystem options to mount a filesystem of type `fs_type` for the user with ids `(uid, gid)`
+    pub fn options_for(&self, fs_type: &str, (uid, gid): (u32, u32)) -> Vec<String> {
+        let mut options = Vec::new();
+
+        if self.hardened {
+            options.extend(["nosuid", "nodev", "noexec"].map(str::to_owned));
+        }
+        if self.sync {
+            options.push("sync".to_owned());
+        }
+        if self.flush && fs_type == "vfat" {
+            options.push("flush".to_owned());
+        }
+        if self.owner_ids && matches!(fs_type, "vfat" | "exfat") {
+            options.push(format!("uid={uid}"));
+            options.push(format!("gid={gid}"));
+        }
+
+        options.extend(self.options.iter().cloned());
+        options
+    }
+}
+
+/// Desktop notifications sent by the daemon when it runs in a user session, for each kind of event
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct NotificationConfig {
+    /// A carrier was mounted and validated, including carriers used for the first time which are never registered
+    pub connected: bool,
+    /// A carrier was registered and its contacts announced
+    pub registered: bool,
+    /// A drive was mounted but is not a file carrier
+    pub not_a_carrier: bool,
+    /// A carrier was ejected and can be unplugged
+    pub safe_to_remove: bool,
+    /// A drive or carrier could not be handled
+    pub errors: bool
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        Self { connected: true, registered: true, not_a_carrier: true, safe_to_remove: true, errors: true }
+    }
+}
+
+/// Executables run by the daemon on carrier events, with details of the event in `ARCHIPELFC_*` environment variables
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct HookConfig {
+    /// Run when a carrier is mounted and validated, before it is registered
+    pub connected: Vec<PathBuf>,
+    /// Run when the contacts of a carrier are announced
+    pub registered: Vec<PathBuf>,
+    /// Run when a carrier is unregistered
+    pub disconnected: Vec<PathBuf>,
+    /// Run when a drive or carrier could not be handled
+    pub error: Vec<PathBuf>,
+    /// Hooks still running after this number of seconds are killed
+    pub timeout: u64
+}
+
+impl Default for HookConfig {
+    fn default() -> Self {
+        Self { connected: Vec::new(), registered: Vec::new(), disconnected: Vec::new(), error: Vec::new(), timeout: 30 }
+    }
+}
+
+impl HookConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+/// Devices the daemon is allowed to handle
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct DeviceFilters {
+    /// If not empty, only devices matching one of these rules are handled
+    pub allow: Vec<DeviceRule>,
+    /// Devices matching one of these rules are never handled, even if allowed
+    pub deny: Vec<DeviceRule>
+}
+
+impl DeviceFilters {
+    /// Returns `true` if the device may be handled
+    pub fn allows(&self, device: &DeviceIdentity) -> bool {
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|rule| rule.matches(device));
+        allowed && !self.deny.iter().any(|rule| rule.matches(device))
+    }
+}
+
+/// Properties identifying a device, empty when unknown
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceIdentity {
+    /// Block device id given by udisks2, such as `by-uuid-1234-ABCD`
+    pub id: String,
+    /// Filesystem UUID
+    pub uuid: String,
+    /// Filesystem label
+    pub label: String,
+    /// Serial number of the drive
+    pub serial: String,
+    pub vendor: String,
+    pub model: String
+}
+
+/// A rule matching devices, every provided property must match
+///
+/// Patterns may contain `*` matching any sequence of characters and `?` matching a single character.
+/// A rule written as a single string matches the block device id.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "RawDeviceRule")]
+pub struct DeviceRule {
+    pub id: Option<String>,
+    pub uuid: Option<String>,
+    pub label: Option<String>,
+    pub serial: Option<String>,
+    pub vendor: Option<String>,
+    pub model: Option<String>
+}
+
+impl DeviceRule {
+    pub fn matches(&self, device: &DeviceIdentity) -> bool {
+        let patterns = [
+            (&self.id, &device.id),
+            (&self.uuid, &device.uuid),
+            (&self.label, &device.label),
+            (&self.serial, &device.serial),
+            (&self.vendor, &device.vendor),
+            (&self.model, &device.model)
+        ];
+
+        patterns.iter().all(|(pattern, value)| match pattern {
+            Some(pattern) => glob_match(pattern, value),
+            None => true
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawDeviceRule {
+    Id(String),
+    Properties(RawDeviceProperties)
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawDeviceProperties {
+    id: Option<String>,
+    uuid: Option<String>,
+    label: Option<String>,
+    serial: Option<String>,
+    vendor: Option<String>,
+    model: Option<String>
+}
+
+impl TryFrom<RawDeviceRule> for DeviceRule {
+    type Error = String;
+
+    fn try_from(value: RawDeviceRule) -> Result<Self, Self::Error> {
+        let rule = match value {
+            RawDeviceRule::Id(id) => Self { id: Some(id), ..Self::default() },
+            RawDeviceRule::Properties(RawDeviceProperties { id, uuid, label, serial, vendor, model }) =>
+                Self { id, uuid, label, serial, vendor, model }
+        };
+
+        // An empty rule would match every device
+        match rule == Self::default() {
+            true => Err("device rule without any of id, uuid, label, serial, vendor or model".to_owned()),
+            false => Ok(rule)
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration from `path` only, or from [SYSTEM_CONFIG] and the user configuration if `None`
+    ///
+    /// Missing files are ignored, except `path`.
+    pub fn load(path: Option<&Path>) -> Result<Self, FileCarrierError> {
+        let files: Vec<PathBuf> = match path {
+            Some(path) => vec![path.to_path_buf()],
+            None => [Some(PathBuf::from(SYSTEM_CONFIG)), user_config_path()].into_iter().flatten().collect()
+        };
+
+        let mut table = Table::new();
+        let mut config = Config::default();
+        for file in files.iter() {
+            let overrides = match read_table(file) {
+                Ok(t) => t,
+                Err(FileCarrierError::IOError(e)) if path.is_none() && e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e)
+            };
+
+            // A file valid on its own may still conflict with the files it overrides, it is the one reported
+            merge(&mut table, overrides);
+            config = Config::deserialize(table.clone())
+                .map_err(|e| FileCarrierError::InvalidConfig(file.clone(), e.to_string()))?;
+        }
+
+        Ok(config)
+    }
+}
+
+impl FromStr for Config {
+    type Err = toml::de::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        toml::from_str(s)
+    }
+}
+
+/// Path of the configuration of the current user, `$XDG_CONFIG_HOME/archipel-fc/config.toml` or `~/.config/archipel-fc/config.toml`
+pub fn user_config_path() -> Option<PathBuf> {
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+    Some(config_home.join("archipel-fc").join("config.toml"))
+}
+
+/// Reads a configuration file, checking it is a valid [Config] on its own
+fn read_table(path: &Path) -> Result<Table, FileCarrierError> {
+    let content = fs::read_to_string(path)?;
+    let invalid = |e: &dyn std::fmt::Display| FileCarrierError::InvalidConfig(path.to_path_buf(), e.to_string());
+
+    let table = content.parse::<Table>().map_err(|e| invalid(&e))?;
+    Config::deserialize(table.clone()).map_err(|e| invalid(&e))?;
+    Ok(table)
+}
+
+/// Merges `overrides` into `base`, tables are merged key by key, any other value is replaced
+fn merge(base: &mut Table, overrides: Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overrides)) => merge(base, overrides),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn from_str<'de, D: Deserializer<'de>, T: FromStr<Err = String>>(deserializer: D) -> Result<T, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    value.parse().map_err(de::Error::custom)
+}
+
+/// Matches `text` against a pattern where `*` matches any sequence of characters and `?` a single character
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` in the pattern, and of the text it was matched against
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            },
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            },
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    t = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                },
+                None => return false
+            }
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path, time::Duration};
+
+    use crate::{config::{glob_match, Config, DeviceFilters, DeviceIdentity, DeviceRule, MountConfig}, strategy::ContactStrategy, testing::TempFolder};
+
+    fn device(id: &str, label: &str, vendor: &str) -> DeviceIdentity {
+        DeviceIdentity { id: id.to_owned(), label: label.to_owned(), vendor: vendor.to_owned(), ..DeviceIdentity::default() }
+    }
+
+    #[test]
+    fn parse_config() {
+        let config: Config = r#"
+            socket = "/tmp/core.socket"
+            strategy = "top-3"
+
+            [daemon]
+            contact-duration = 60
+            auto-init-label = "ARCHIPEL*"
+            eject-after = 120
+
+            [mount]
+            as-user = "archipel"
+            options = ["noatime"]
+
+            [notifications]
+            not-a-carrier = false
+
+            [hooks]
+            registered = ["/usr/local/bin/blink-led"]
+
+            [devices]
+            allow = [{ label = "ARCHIPEL*" }]
+            deny = ["by-id-ata-*", { vendor = "Seagate", model = "Expansion*" }]
+        "#.parse().unwrap();
+
+        assert_eq!(config.socket, Path::new("/tmp/core.socket"));
+        assert_eq!(config.strategy, ContactStrategy::TopFrequent(3));
+        assert_eq!(config.daemon.contact_duration, 60);
+        assert_eq!(config.cli.contact_duration, 300);
+        assert!(config.daemon.auto_initializes("ARCHIPEL-03"));
+        assert_eq!(config.daemon.eject_after(), Some(Duration::from_secs(120)));
+        assert!(!config.daemon.auto_initializes("BACKUP"));
+        assert!(!Config::default().daemon.auto_initializes("ARCHIPEL-03"));
+        assert_eq!(config.mount.as_user.as_deref(), Some("archipel"));
+        assert_eq!(config.mount.options, vec!["noatime"]);
+        assert!(config.notifications.registered);
+        assert!(!config.notifications.not_a_carrier);
+        assert_eq!(config.hooks.registered, vec![Path::new("/usr/local/bin/blink-led")]);
+        assert_eq!(config.hooks.timeout(), Duration::from_secs(30));
+        assert!(config.devices.allows(&device("by-uuid-1", "ARCHIPEL-01", "SanDisk")));
+        assert!(!config.devices.allows(&device("by-id-ata-disk", "ARCHIPEL-02", "SanDisk")));
+        assert!(!config.devices.allows(&device("by-uuid-2", "BACKUP", "SanDisk")));
+        assert_eq!(config.devices.deny[1], DeviceRule {
+            vendor: Some("Seagate".to_owned()),
+            model: Some("Expansion*".to_owned()),
+            ..DeviceRule::default()
+        });
+
+        assert!("strategy = \"sometimes\"".parse::<Config>().is_err());
+        assert!("unknown = 1".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn mount_options() {
+        let config = MountConfig { flush: true, options: vec!["noatime".to_owned()], ..MountConfig::default() };
+        assert_eq!(config.options_for("vfat", (1000, 100)), vec!["nosuid", "nodev", "noexec", "flush", "uid=1000", "gid=100", "noatime"]);
+        assert_eq!(config.options_for("exfat", (1000, 100)), vec!["nosuid", "nodev", "noexec", "uid=1000", "gid=100", "noatime"]);
+        assert_eq!(config.options_for("ext4", (1000, 100)), vec!["nosuid", "nodev", "noexec", "noatime"]);
+
+        let config = MountConfig { hardened: false, sync: true, owner_ids: false, ..MountConfig::default() };
+        assert_eq!(config.options_for("vfat", (1000, 100)), vec!["sync"]);
+    }
+
+    #[test]
+    fn load_explicit_file() {
+        let folder = TempFolder::new("fc-config").unwrap();
+        let path = folder.path().join("config.toml");
+        fs::write(&path, "[cli]\ncontact-duration = 10").unwrap();
+
+        let config = Config::load(Some(&path)).unwrap();
+        assert_eq!(config.cli.contact_duration, 10);
+        assert_eq!(config.socket, Config::default().socket);
+
+        assert!(Config::load(Some(&folder.path().join("missing.toml"))).is_err());
+    }
+
+    #[test]
+    fn filters() {
+        let filters = DeviceFilters {
+            allow: vec![DeviceRule { label: Some("ARCHIPEL*".to_owned()), ..DeviceRule::default() }],
+            deny: vec![DeviceRule { label: Some("ARCHIPEL*".to_owned()), vendor: Some("Kingston".to_owned()), ..DeviceRule::default() }]
+        };
+        assert!(filters.allows(&device("a", "ARCHIPEL-01", "SanDisk")));
+        assert!(!filters.allows(&device("b", "ARCHIPEL-02", "Kingston")));
+        assert!(!filters.allows(&device("c", "photos", "SanDisk")));
+        assert!(DeviceFilters::default().allows(&DeviceIdentity::default()));
+
+        // Misspelled properties and empty rules are rejected instead of matching every device
+        assert!("[devices]\nallow = [{ lable = \"ARCHIPEL*\" }]".parse::<Config>().is_err());
+        assert!("[devices]\nallow = [{}]".parse::<Config>().is_err());
+        assert!("[devices]\nallow = [{ label = \"ARCHIPEL*\" }]".parse::<Config>().is_ok());
+
+        assert!(glob_match("ARCHIPEL*", "ARCHIPEL-01"));
+        assert!(glob_match("a?c*d", "abcxxd"));
+        assert!(!glob_match("a*b", "acbc"));
+    }
+}
@@ -9,6 +9,14 @@ pub enum FileCarrierError {
     IOError(#[from] std::io::Error),
     #[error("{0} is not a file carrier")]
     NotAFileCarrier(PathBuf),
-    #[error("You are the first to use {0} as a file carrier")]
-    FirstUser(PathBuf)
+    #[error("{0} uses layout version {1} but only versions up to {2} are supported")]
+    UnsupportedVersion(PathBuf, u32, u32),
+    #[error("Invalid manifest {0}: {1}")]
+    InvalidManifest(PathBuf, String),
+    #[error("Invalid reaches file {0}: {1}")]
+    InvalidReaches(PathBuf, String),
+    #[error("Invalid configuration {0}: {1}")]
+    InvalidConfig(PathBuf, String),
+    #[error("{0} is not a valid UTF-8 path, it can't be used as a CLA address")]
+    NonUtf8Path(PathBuf)
 }
\ No newline at end of file
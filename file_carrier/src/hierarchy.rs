@@ -1,10 +1,125 @@
-use std::{fs::{self, create_dir_all, File, Permissions}, io, os::unix::fs::PermissionsExt, path::{Path, PathBuf}};
+use std::{fmt::Display, fs::{self, create_dir_all, File, Permissions}, io::{self, Write}, os::unix::fs::PermissionsExt, path::{Path, PathBuf}, str::FromStr, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use uuid::Uuid;
+
+use crate::{error::FileCarrierError, rate::DataRate, reaches::ReachesHistory};
+
+/// Layout version of the hierarchy written by this version of the code
+pub const LAYOUT_VERSION: u32 = 3;
+
+/// Content of the `.bundles/manifest` file describing a file carrier
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CarrierManifest {
+    /// Layout version the carrier was formatted with
+    pub version: u32,
+    /// Persistent identifier of the carrier
+    pub uuid: Uuid,
+    /// Date of creation of the carrier
+    pub created: SystemTime,
+    /// Name and version of the tool which created the carrier
+    pub tool_version: String
+}
+
+impl CarrierManifest {
+    /// Creates a new [CarrierManifest] for a carrier formatted now with the current [LAYOUT_VERSION]
+    pub fn new() -> Self {
+        let created = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| UNIX_EPOCH + Duration::from_secs(d.as_secs()))
+            .unwrap_or(UNIX_EPOCH);
+        Self {
+            version: LAYOUT_VERSION,
+            uuid: Uuid::new_v4(),
+            created,
+            tool_version: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+        }
+    }
+
+    /// Returns the EID identifying the contact at `index` announced for this carrier
+    ///
+    /// Contacts are identified by the carrier instead of the nodes they reach so contacts
+    /// of several carriers connected at the same time never collide.
+    pub fn contact_eid(&self, index: usize) -> String {
+        match index {
+            0 => format!("dtn://file-carrier-{}/", self.uuid),
+            _ => format!("dtn://file-carrier-{}-{}/", self.uuid, index)
+        }
+    }
+
+    /// Returns the EID of the contact announcing the predicted next visits for the contact at `index`
+    ///
+    /// Predictions are needed once the carrier left, so they are kept apart from the contacts deleted at unregistration.
+    pub fn predicted_contact_eid(&self, index: usize) -> String {
+        match index {
+            0 => format!("dtn://file-carrier-{}-predicted/", self.uuid),
+            _ => format!("dtn://file-carrier-{}-predicted-{}/", self.uuid, index)
+        }
+    }
+}
+
+impl Default for CarrierManifest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FromStr for CarrierManifest {
+    type Err = String;
+
+    /// Parses a manifest made of `key=value` lines, unknown keys are ignored
+    fn from_str(content: &str) -> Result<Self, Self::Err> {
+        let mut version = None;
+        let mut uuid = None;
+        let mut created = None;
+        let mut tool_version = None;
+
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(format!("malformed line \"{line}\""));
+            };
+            let value = value.trim();
+
+            match key.trim() {
+                "version" => version = Some(value.parse::<u32>()
+                    .map_err(|e| format!("invalid version \"{value}\": {e}"))?),
+                "uuid" => uuid = Some(Uuid::parse_str(value)
+                    .map_err(|e| format!("invalid uuid \"{value}\": {e}"))?),
+                "created" => created = Some(UNIX_EPOCH + Duration::from_secs(value.parse::<u64>()
+                    .map_err(|e| format!("invalid creation date \"{value}\": {e}"))?)),
+                "tool" => tool_version = Some(value.to_owned()),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            version: version.ok_or("missing version")?,
+            uuid: uuid.ok_or("missing uuid")?,
+            created: created.ok_or("missing creation date")?,
+            tool_version: tool_version.unwrap_or_default()
+        })
+    }
+}
+
+impl Display for CarrierManifest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let created = self.created.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        writeln!(f, "version={}", self.version)?;
+        writeln!(f, "uuid={}", self.uuid)?;
+        writeln!(f, "created={}", created)?;
+        writeln!(f, "tool={}", self.tool_version)
+    }
+}
 
 pub struct FileCarrierHierarchy {
     root: PathBuf,
     data: PathBuf,
     reaches_file: PathBuf,
-    connected_file: PathBuf
+    connected_file: PathBuf,
+    manifest_file: PathBuf,
+    rate_file: PathBuf
 }
 
 impl FileCarrierHierarchy {
@@ -17,11 +132,15 @@ impl FileCarrierHierarchy {
         let data = root.join("data");
         let reaches_file = root.join("reaches");
         let connected_file = root.join(".connected");
+        let manifest_file = root.join("manifest");
+        let rate_file = root.join("rate");
         Self {
             root,
             data,
             reaches_file,
-            connected_file
+            connected_file,
+            manifest_file,
+            rate_file
         }
     }
 
@@ -41,6 +160,79 @@ impl FileCarrierHierarchy {
         &self.connected_file
     }
 
+    pub fn manifest_file(&self) -> &Path {
+        &self.manifest_file
+    }
+
+    pub fn rate_file(&self) -> &Path {
+        &self.rate_file
+    }
+
+    /// Reads the [CarrierManifest] of this hierarchy
+    ///
+    /// Returns `Ok(None)` if the carrier has no manifest (carriers formatted before manifests existed)
+    /// and [FileCarrierError::UnsupportedVersion] if the carrier uses a layout newer than [LAYOUT_VERSION]
+    pub fn read_manifest(&self) -> Result<Option<CarrierManifest>, FileCarrierError> {
+        let content = match fs::read_to_string(&self.manifest_file) {
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into())
+        };
+
+        let manifest: CarrierManifest = content.parse()
+            .map_err(|e| FileCarrierError::InvalidManifest(self.manifest_file.clone(), e))?;
+
+        if manifest.version > LAYOUT_VERSION {
+            return Err(FileCarrierError::UnsupportedVersion(self.root.clone(), manifest.version, LAYOUT_VERSION));
+        }
+
+        Ok(Some(manifest))
+    }
+
+    /// Reads the [ReachesHistory] of nodes reached by this carrier
+    pub fn reaches(&self) -> Result<ReachesHistory, FileCarrierError> {
+        let content = match fs::read_to_string(&self.reaches_file) {
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(ReachesHistory::default()),
+            Err(e) => return Err(e.into())
+        };
+
+        content.parse()
+            .map_err(|e| FileCarrierError::InvalidReaches(self.reaches_file.clone(), e))
+    }
+
+    /// Writes the provided [ReachesHistory] in this hierarchy, replacing the existing one
+    pub fn write_reaches(&self, reaches: &ReachesHistory) -> io::Result<()> {
+        File::create(&self.reaches_file)?.write_all(reaches.to_string().as_bytes())
+    }
+
+    /// Caches the measured [DataRate] of the carrier, readable and writable by every user
+    pub fn write_rate(&self, rate: &DataRate) -> io::Result<()> {
+        File::create(&self.rate_file)?.write_all(rate.to_string().as_bytes())?;
+        fs::set_permissions(&self.rate_file, Permissions::from_mode(0o666))?;
+        Ok(())
+    }
+
+    /// Writes the provided [CarrierManifest] in this hierarchy, replacing any existing one
+    pub fn write_manifest(&self, manifest: &CarrierManifest) -> io::Result<()> {
+        File::create(&self.manifest_file)?.write_all(manifest.to_string().as_bytes())?;
+        fs::set_permissions(&self.manifest_file, Permissions::from_mode(0o666))?;
+        Ok(())
+    }
+
+    /// Returns the last time a bundle was written to or removed from the data folder
+    pub fn last_data_activity(&self) -> io::Result<SystemTime> {
+        // Removing a file updates the modification time of the folder
+        let mut last = fs::metadata(&self.data)?.modified()?;
+
+        for entry in fs::read_dir(&self.data)? {
+            let modified = entry?.metadata()?.modified()?;
+            last = last.max(modified);
+        }
+
+        Ok(last)
+    }
+
     /// Returns a `Ok(true)` if the [FileCarrierHierarchy] already exists
     pub fn try_exists(&self) -> io::Result<bool> {
         Ok(self.data.try_exists()? && self.root.try_exists()?)
@@ -79,4 +271,62 @@ impl FileCarrierHierarchy {
         File::create(hierarchy.reaches_file.to_owned())?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{error::FileCarrierError, hierarchy::{CarrierManifest, FileCarrierHierarchy, LAYOUT_VERSION}};
+
+    #[test]
+    fn manifest_roundtrip() {
+        let manifest = CarrierManifest::new();
+        let parsed: CarrierManifest = manifest.to_string().parse().unwrap();
+        assert_eq!(manifest, parsed);
+        assert_eq!(parsed.version, LAYOUT_VERSION);
+    }
+
+    #[test]
+    fn contact_eid_is_per_carrier() {
+        let first = CarrierManifest::new();
+        let second = CarrierManifest::new();
+        assert_ne!(first.contact_eid(0), second.contact_eid(0));
+        assert_ne!(first.contact_eid(0), first.contact_eid(1));
+        assert_ne!(first.contact_eid(0), first.predicted_contact_eid(0));
+        assert_eq!(first.contact_eid(0), format!("dtn://file-carrier-{}/", first.uuid));
+    }
+
+    #[test]
+    fn manifest_newer_version_refused() {
+        let folder = std::env::temp_dir().join(format!("fc-manifest-{}", uuid::Uuid::new_v4()));
+        let hierarchy = FileCarrierHierarchy::new(&folder);
+        hierarchy.create_hierarchy().unwrap();
+
+        let mut manifest = CarrierManifest::new();
+        manifest.version = LAYOUT_VERSION + 1;
+        hierarchy.write_manifest(&manifest).unwrap();
+
+        let res = hierarchy.read_manifest();
+        let _ = std::fs::remove_dir_all(&folder);
+
+        assert!(matches!(res, Err(FileCarrierError::UnsupportedVersion(_, v, _)) if v == LAYOUT_VERSION + 1));
+    }
+
+    #[test]
+    fn data_activity() {
+        let folder = std::env::temp_dir().join(format!("fc-activity-{}", uuid::Uuid::new_v4()));
+        let hierarchy = FileCarrierHierarchy::new(&folder);
+        hierarchy.create_hierarchy().unwrap();
+
+        let before = hierarchy.last_data_activity().unwrap();
+        let bundle = hierarchy.data().join("a.bundle7");
+        std::fs::write(&bundle, b"bundle").unwrap();
+        let file = std::fs::File::options().write(true).open(&bundle).unwrap();
+        file.set_modified(before + std::time::Duration::from_secs(60)).unwrap();
+        drop(file);
+
+        let after = hierarchy.last_data_activity().unwrap();
+        let _ = std::fs::remove_dir_all(&folder);
+
+        assert_eq!(after, before + std::time::Duration::from_secs(60));
+    }
+}
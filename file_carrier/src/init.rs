@@ -1,28 +1,35 @@
-use std::{path::Path, io::{self, Write}, fs::File};
+use std::{path::{Path, PathBuf}, io::{self, Write}, fs::File};
 
-use crate::hierarchy::FileCarrierHierarchy;
+use crate::hierarchy::{CarrierManifest, FileCarrierHierarchy};
 
 const README: &str = include_str!("templates/readme.txt");
 
+/// Outcome of [initialize_file_carrier]
+#[derive(Debug, Clone)]
+pub struct InitReport {
+    /// Canonical path of the folder containing the `.bundles` directory
+    pub folder: PathBuf,
+    /// `true` if the folder was already a file carrier and was left untouched
+    pub already_initialized: bool
+}
+
 /// Initialize a File Carrier hierarchy
 /// # Arguments
 ///
 /// * `path` - A [&Path] leading to the folder which will contains the `.bundles` directory
-pub fn initialize_file_carrier(path: &Path) -> io::Result<()>{
+pub fn initialize_file_carrier(path: &Path) -> io::Result<InitReport>{
     let hierarchy = FileCarrierHierarchy::new(path);
 
     if hierarchy.try_exists()? {
-        println!("{:?} is already a file carrier", path);
-        return Ok(());
+        return Ok(InitReport { folder: path.canonicalize()?, already_initialized: true });
     }
 
     hierarchy.create_hierarchy()?;
+    hierarchy.write_manifest(&CarrierManifest::new())?;
 
     File::create(hierarchy.root().join("readme.txt"))?.write_all(README.as_bytes())?;
 
-    println!("File carrier initialized in {}", path.canonicalize()?.display());
-
-    Ok(())
+    Ok(InitReport { folder: path.canonicalize()?, already_initialized: false })
 }
 
 #[cfg(test)]
@@ -39,9 +46,11 @@ mod tests {
         let bundle_path = current_dir.join(".bundles");
         let data_path = current_dir.join(".bundles/data");
         let reaches_path = current_dir.join(".bundles/reaches");
+        let manifest_path = current_dir.join(".bundles/manifest");
 
         
         assert!(bundle_path.try_exists().unwrap() && data_path.try_exists().unwrap() && reaches_path.try_exists().unwrap());
+        assert!(manifest_path.try_exists().unwrap());
         let _ = fs::remove_dir_all(bundle_path);
     }
 }
\ No newline at end of file
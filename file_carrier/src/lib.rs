@@ -1,10 +1,19 @@
 use std::{path::PathBuf, str::FromStr};
 
+pub mod config;
 pub mod error;
 pub mod unregister;
 pub mod register;
 pub mod init;
 pub mod hierarchy;
+pub mod migration;
+pub mod prediction;
+pub mod rate;
+pub mod reaches;
+pub mod reliability;
+pub mod strategy;
+#[cfg(any(test, feature = "test-support"))]
+pub mod testing;
 
 extern "C" {
     fn geteuid() -> u32;
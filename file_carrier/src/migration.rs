@@ -0,0 +1,251 @@
+use std::{fs, io, path::{Path, PathBuf}, time::{Duration, UNIX_EPOCH}};
+
+use crate::{error::FileCarrierError, hierarchy::{CarrierManifest, FileCarrierHierarchy, LAYOUT_VERSION}};
+
+/// A step upgrading the hierarchy from one layout version to the next one
+type MigrationStep = fn(&FileCarrierHierarchy) -> Result<(), FileCarrierError>;
+
+/// Migration steps, the step at index `i` upgrades a hierarchy from layout version `i` to `i + 1`
+const STEPS: [MigrationStep; LAYOUT_VERSION as usize] = [
+    legacy_to_manifest,
+    structured_reaches,
+    dated_visits,
+];
+
+const STAGING_DIR: &str = ".migration";
+const BACKUP_DIR: &str = ".migration-backup";
+const ADDED_LIST: &str = ".added";
+
+/// Result of a successful [migrate] call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationOutcome {
+    /// The hierarchy was already at the provided layout version
+    UpToDate(u32),
+    /// The hierarchy was upgraded between the provided layout versions
+    Migrated { from: u32, to: u32 }
+}
+
+/// Returns the layout version of a hierarchy, carriers without manifest are version `0`
+pub fn layout_version(hierarchy: &FileCarrierHierarchy) -> Result<u32, FileCarrierError> {
+    Ok(hierarchy.read_manifest()?.map(|m| m.version).unwrap_or(0))
+}
+
+/// Upgrades a file carrier hierarchy in place to the current [LAYOUT_VERSION]
+///
+/// Metadata files are copied to a staging folder where every needed [MigrationStep] is applied,
+/// then swapped with the original ones. If anything fails the original files are restored.
+/// Bundles in the `data` folder are never touched.
+/// # Argument
+///
+/// * `folder` - A [&Path] leading to the folder which contains the `.bundles` directory
+pub fn migrate(folder: &Path) -> Result<MigrationOutcome, FileCarrierError> {
+    let hierarchy = FileCarrierHierarchy::new(folder);
+
+    if !hierarchy.try_exists()? {
+        return Err(FileCarrierError::NotAFileCarrier(folder.to_path_buf()));
+    }
+
+    // A backup left behind means a previous migration was interrupted while swapping files
+    let backup = hierarchy.root().join(BACKUP_DIR);
+    if backup.try_exists()? {
+        restore_backup(hierarchy.root(), &backup)?;
+    }
+
+    let from = layout_version(&hierarchy)?;
+    if from == LAYOUT_VERSION {
+        return Ok(MigrationOutcome::UpToDate(from));
+    }
+
+    let staging_folder = hierarchy.root().join(STAGING_DIR);
+    let result = stage(&hierarchy, &staging_folder)
+        .and_then(|staging| {
+            apply_steps(&staging, from)?;
+            commit(&hierarchy, &staging)?;
+            Ok(())
+        });
+    let _ = fs::remove_dir_all(&staging_folder);
+
+    result.map(|_| MigrationOutcome::Migrated { from, to: LAYOUT_VERSION })
+}
+
+/// Copies metadata files of the hierarchy into a staging hierarchy
+fn stage(hierarchy: &FileCarrierHierarchy, staging_folder: &Path) -> Result<FileCarrierHierarchy, FileCarrierError> {
+    if staging_folder.try_exists()? {
+        fs::remove_dir_all(staging_folder)?;
+    }
+
+    let staging = FileCarrierHierarchy::new(staging_folder);
+    fs::create_dir_all(staging.data())?;
+
+    for file in metadata_files(hierarchy.root())? {
+        fs::copy(hierarchy.root().join(&file), staging.root().join(&file))?;
+    }
+
+    Ok(staging)
+}
+
+/// Applies every migration step needed to bring the staging hierarchy from `from` to [LAYOUT_VERSION]
+fn apply_steps(staging: &FileCarrierHierarchy, from: u32) -> Result<(), FileCarrierError> {
+    for version in from..LAYOUT_VERSION {
+        STEPS[version as usize](staging)?;
+
+        let Some(mut manifest) = staging.read_manifest()? else {
+            return Err(FileCarrierError::InvalidManifest(staging.manifest_file().to_path_buf(), "missing after migration".to_owned()));
+        };
+        manifest.version = version + 1;
+        staging.write_manifest(&manifest)?;
+    }
+
+    Ok(())
+}
+
+/// Swaps metadata files of the hierarchy with the staged ones, restoring originals on failure
+fn commit(hierarchy: &FileCarrierHierarchy, staging: &FileCarrierHierarchy) -> io::Result<()> {
+    let originals = metadata_files(hierarchy.root())?;
+    let staged = metadata_files(staging.root())?;
+
+    // Files without original are recorded so an interrupted swap can be rolled back later
+    let added: Vec<&PathBuf> = staged.iter().filter(|f| !originals.contains(f)).collect();
+    let backup = hierarchy.root().join(BACKUP_DIR);
+    fs::create_dir_all(&backup)?;
+    fs::write(backup.join(ADDED_LIST), added.iter()
+        .map(|f| f.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("\n"))?;
+
+    let swap = || -> io::Result<()> {
+        for file in originals.iter() {
+            fs::rename(hierarchy.root().join(file), backup.join(file))?;
+        }
+        for file in staged.iter() {
+            fs::rename(staging.root().join(file), hierarchy.root().join(file))?;
+        }
+        Ok(())
+    };
+
+    if let Err(e) = swap() {
+        restore_backup(hierarchy.root(), &backup)?;
+        return Err(e);
+    }
+
+    fs::remove_dir_all(&backup)
+}
+
+/// Puts back files saved in `backup` into `root` and removes files added by the interrupted swap
+fn restore_backup(root: &Path, backup: &Path) -> io::Result<()> {
+    let added = match fs::read_to_string(backup.join(ADDED_LIST)) {
+        Ok(list) => list,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e)
+    };
+
+    for file in added.lines().filter(|l| !l.is_empty()) {
+        match fs::remove_file(root.join(file)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+
+    for file in metadata_files(backup)? {
+        if file.as_os_str() != ADDED_LIST {
+            fs::rename(backup.join(&file), root.join(&file))?;
+        }
+    }
+
+    fs::remove_dir_all(backup)
+}
+
+/// Lists regular files directly in the provided folder
+fn metadata_files(folder: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(folder)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            files.push(PathBuf::from(entry.file_name()));
+        }
+    }
+    Ok(files)
+}
+
+/// Layout 0 to 1 : carriers formatted before manifests existed get one
+fn legacy_to_manifest(hierarchy: &FileCarrierHierarchy) -> Result<(), FileCarrierError> {
+    let mut manifest = CarrierManifest::new();
+    manifest.version = 0;
+
+    // Best guess of the creation date is the last change of the `reaches` file
+    if let Some(created) = fs::metadata(hierarchy.reaches_file()).and_then(|m| m.modified()).ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok()) {
+        manifest.created = UNIX_EPOCH + Duration::from_secs(created.as_secs());
+    }
+
+    hierarchy.write_manifest(&manifest)?;
+    Ok(())
+}
+
+/// Layout 1 to 2 : `reaches` file goes from one EID per line to a structured history
+fn structured_reaches(hierarchy: &FileCarrierHierarchy) -> Result<(), FileCarrierError> {
+    let reaches = hierarchy.reaches()?;
+    hierarchy.write_reaches(&reaches)?;
+    Ok(())
+}
+
+/// Layout 2 to 3 : records of the `reaches` file get a count of dated visits
+///
+/// Every visit of a node with known dates is assumed dated, as layout 2 has no way to tell.
+fn dated_visits(hierarchy: &FileCarrierHierarchy) -> Result<(), FileCarrierError> {
+    let reaches = hierarchy.reaches()?;
+    hierarchy.write_reaches(&reaches)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{hierarchy::{CarrierManifest, FileCarrierHierarchy, LAYOUT_VERSION}, migration::{layout_version, migrate, MigrationOutcome}};
+
+    #[test]
+    fn migrate_legacy_carrier() {
+        let folder = std::env::temp_dir().join(format!("fc-migration-{}", uuid::Uuid::new_v4()));
+        let hierarchy = FileCarrierHierarchy::new(&folder);
+        hierarchy.create_hierarchy().unwrap();
+        fs::write(hierarchy.reaches_file(), "dtn://a/\ndtn://b/").unwrap();
+        fs::write(hierarchy.data().join("1.bundle7"), "bundle").unwrap();
+
+        let outcome = migrate(&folder).unwrap();
+        let version = layout_version(&hierarchy).unwrap();
+        let reaches = fs::read_to_string(hierarchy.reaches_file()).unwrap();
+        let bundle = fs::read_to_string(hierarchy.data().join("1.bundle7")).unwrap();
+        let again = migrate(&folder).unwrap();
+        let leftovers = hierarchy.root().join(".migration").try_exists().unwrap()
+            || hierarchy.root().join(".migration-backup").try_exists().unwrap();
+
+        let _ = fs::remove_dir_all(&folder);
+
+        assert_eq!(outcome, MigrationOutcome::Migrated { from: 0, to: LAYOUT_VERSION });
+        assert_eq!(version, LAYOUT_VERSION);
+        assert_eq!(bundle, "bundle");
+        assert!(reaches.contains("dtn://a/ - - 1 - 0\ndtn://b/ - - 1 - 0"));
+        assert_eq!(again, MigrationOutcome::UpToDate(LAYOUT_VERSION));
+        assert!(!leftovers);
+    }
+
+    #[test]
+    fn migrate_reaches_without_dated_visits() {
+        let folder = std::env::temp_dir().join(format!("fc-migration-{}", uuid::Uuid::new_v4()));
+        let hierarchy = FileCarrierHierarchy::new(&folder);
+        hierarchy.create_hierarchy().unwrap();
+        let mut manifest = CarrierManifest::new();
+        manifest.version = 2;
+        hierarchy.write_manifest(&manifest).unwrap();
+        fs::write(hierarchy.reaches_file(), "# eid first_seen last_seen visits last_sync\ndtn://a/ 1000 3000 3 60\ndtn://b/ - - 1 -").unwrap();
+
+        let outcome = migrate(&folder).unwrap();
+        let reaches = fs::read_to_string(hierarchy.reaches_file()).unwrap();
+
+        let _ = fs::remove_dir_all(&folder);
+
+        assert_eq!(outcome, MigrationOutcome::Migrated { from: 2, to: LAYOUT_VERSION });
+        assert!(reaches.contains("dtn://a/ 1000 3000 3 60 3\ndtn://b/ - - 1 - 0"));
+    }
+}
@@ -0,0 +1,119 @@
+use std::time::{Duration, SystemTime};
+
+use crate::reaches::ReachRecord;
+
+/// Number of visits needed before the return period of a carrier is trusted
+pub const MIN_VISITS: u32 = 3;
+
+/// Return periods shorter than this are considered as replugs, not trips
+pub const MIN_PERIOD: Duration = Duration::from_secs(3600);
+
+/// Window length used when the duration of the previous connection is unknown
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(3600);
+
+/// A time window during which the carrier is expected to be connected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContactWindow {
+    pub start: SystemTime,
+    pub end: SystemTime
+}
+
+/// Predicts the next times a carrier will come back to a node from its visit history
+///
+/// The return period is the mean interval between the first and last dated visits of the node,
+/// each predicted window lasts as long as the last connection to the node.
+/// No prediction is made until the node was visited [MIN_VISITS] times at known dates.
+/// # Arguments
+///
+/// * `record` - The [ReachRecord] of the node, including the current visit
+/// * `not_before` - No predicted window starts before this time (usually the end of the current contact)
+/// * `count` - Maximum number of windows to predict
+pub fn predict_contacts(record: &ReachRecord, not_before: SystemTime, count: usize) -> Vec<ContactWindow> {
+    let (Some(first_seen), Some(last_seen)) = (record.first_seen, record.last_seen) else {
+        return Vec::new();
+    };
+
+    if record.dated_visits < MIN_VISITS {
+        return Vec::new();
+    }
+
+    let Ok(span) = last_seen.duration_since(first_seen) else {
+        return Vec::new();
+    };
+
+    let period = span / (record.dated_visits - 1);
+    if period < MIN_PERIOD {
+        return Vec::new();
+    }
+
+    let length = record.last_sync.unwrap_or(DEFAULT_WINDOW).min(period);
+
+    (1..)
+        .map(|n| last_seen + period * n)
+        .filter(|start| *start >= not_before)
+        .take(count)
+        .map(|start| ContactWindow { start, end: start + length })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::{prediction::{predict_contacts, ContactWindow}, reaches::{ReachRecord, ReachesHistory}};
+
+    const DAY: u64 = 24 * 3600;
+
+    fn weekly_record(visits: u32) -> ReachRecord {
+        ReachRecord {
+            eid: "dtn://a/".to_owned(),
+            first_seen: Some(UNIX_EPOCH),
+            last_seen: Some(UNIX_EPOCH + Duration::from_secs(7 * DAY * (visits as u64 - 1))),
+            visits,
+            dated_visits: visits,
+            last_sync: Some(Duration::from_secs(1800))
+        }
+    }
+
+    #[test]
+    fn weekly_carrier() {
+        let record = weekly_record(4);
+        let last_seen = record.last_seen.unwrap();
+
+        let windows = predict_contacts(&record, last_seen + Duration::from_secs(600), 2);
+
+        assert_eq!(windows, vec![
+            ContactWindow { start: last_seen + Duration::from_secs(7 * DAY), end: last_seen + Duration::from_secs(7 * DAY + 1800) },
+            ContactWindow { start: last_seen + Duration::from_secs(14 * DAY), end: last_seen + Duration::from_secs(14 * DAY + 1800) },
+        ]);
+    }
+
+    #[test]
+    fn not_enough_history() {
+        let record = weekly_record(2);
+        assert!(predict_contacts(&record, UNIX_EPOCH, 2).is_empty());
+
+        let legacy = ReachRecord::new("dtn://a/".to_owned());
+        assert!(predict_contacts(&legacy, UNIX_EPOCH, 2).is_empty());
+    }
+
+    #[test]
+    fn migrated_legacy_record() {
+        // The visit imported from the legacy format has no date and is not part of the period
+        let mut history: ReachesHistory = "dtn://a/".parse().unwrap();
+        for week in 0..3 {
+            history.record_visit("dtn://a/", UNIX_EPOCH + Duration::from_secs(7 * DAY * week));
+        }
+
+        let record = history.get("dtn://a/").unwrap();
+        assert_eq!(record.visits, 4);
+        let last_seen = record.last_seen.unwrap();
+        let windows = predict_contacts(record, last_seen, 1);
+        assert_eq!(windows[0].start, last_seen + Duration::from_secs(7 * DAY));
+
+        let mut history: ReachesHistory = "dtn://a/".parse().unwrap();
+        history.record_visit("dtn://a/", UNIX_EPOCH);
+        history.record_visit("dtn://a/", UNIX_EPOCH + Duration::from_secs(7 * DAY));
+        assert!(predict_contacts(history.get("dtn://a/").unwrap(), UNIX_EPOCH, 1).is_empty());
+    }
+}
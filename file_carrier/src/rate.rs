@@ -0,0 +1,163 @@
+use std::{fmt::Display, fs::{self, File, OpenOptions}, io::{self, Read, Write}, os::fd::AsRawFd, path::Path, str::FromStr, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use crate::{error::FileCarrierError, hierarchy::FileCarrierHierarchy};
+
+/// Amount of data written and read back to measure the carrier throughput
+pub const BENCHMARK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Age after which a measured rate is measured again
+pub const BENCHMARK_VALIDITY: Duration = Duration::from_secs(7 * 24 * 3600);
+
+const BENCHMARK_FILE: &str = ".rate-benchmark";
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Throughput of a carrier, cached in the `.bundles/rate` file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataRate {
+    /// Write throughput in bytes per second
+    pub write: u64,
+    /// Read throughput in bytes per second
+    pub read: u64,
+    /// Date of the measure
+    pub measured: SystemTime
+}
+
+impl DataRate {
+    /// Throughput usable for bundle transfer in bytes per second, the slowest of read and write
+    pub fn bytes_per_second(&self) -> u64 {
+        self.write.min(self.read)
+    }
+
+    /// Returns `true` if the measure is older than [BENCHMARK_VALIDITY]
+    pub fn is_outdated(&self, now: SystemTime) -> bool {
+        now.duration_since(self.measured).map(|age| age > BENCHMARK_VALIDITY).unwrap_or(true)
+    }
+}
+
+impl FromStr for DataRate {
+    type Err = String;
+
+    fn from_str(content: &str) -> Result<Self, Self::Err> {
+        let mut write = None;
+        let mut read = None;
+        let mut measured = None;
+
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(format!("malformed line \"{line}\""));
+            };
+            let value: u64 = value.trim().parse()
+                .map_err(|e| format!("invalid value for {key}: {e}"))?;
+
+            match key.trim() {
+                "write" => write = Some(value),
+                "read" => read = Some(value),
+                "measured" => measured = Some(UNIX_EPOCH + Duration::from_secs(value)),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            write: write.ok_or("missing write rate")?,
+            read: read.ok_or("missing read rate")?,
+            measured: measured.ok_or("missing measure date")?
+        })
+    }
+}
+
+impl Display for DataRate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "write={}", self.write)?;
+        writeln!(f, "read={}", self.read)?;
+        writeln!(f, "measured={}", self.measured.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs())
+    }
+}
+
+/// Returns the [DataRate] of a carrier, measuring it if the cached one is missing or outdated
+pub fn carrier_data_rate(hierarchy: &FileCarrierHierarchy) -> Result<DataRate, FileCarrierError> {
+    let now = SystemTime::now();
+
+    let cached = fs::read_to_string(hierarchy.rate_file()).ok()
+        .and_then(|content| content.parse::<DataRate>().ok())
+        .filter(|rate| !rate.is_outdated(now));
+
+    if let Some(rate) = cached {
+        return Ok(rate);
+    }
+
+    let rate = benchmark(hierarchy.data())?;
+
+    // Failing to cache only means measuring again next time
+    let _ = hierarchy.write_rate(&rate);
+
+    Ok(rate)
+}
+
+/// Measures write and read throughput by writing and reading back [BENCHMARK_SIZE] bytes in `folder`
+pub fn benchmark(folder: &Path) -> io::Result<DataRate> {
+    let path = folder.join(BENCHMARK_FILE);
+    let result = run_benchmark(&path);
+    let _ = fs::remove_file(&path);
+    result
+}
+
+fn run_benchmark(path: &Path) -> io::Result<DataRate> {
+    let chunk = vec![0xA5u8; CHUNK_SIZE];
+
+    let start = Instant::now();
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(path)?;
+    for _ in 0..(BENCHMARK_SIZE / CHUNK_SIZE) {
+        file.write_all(&chunk)?;
+    }
+    file.sync_all()?;
+    let write = rate(BENCHMARK_SIZE, start.elapsed());
+
+    // Evict the file from the page cache, otherwise reading measures memory instead of the drive
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED);
+    }
+    drop(file);
+
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let start = Instant::now();
+    let mut file = File::open(path)?;
+    while file.read(&mut buffer)? > 0 {}
+    let read = rate(BENCHMARK_SIZE, start.elapsed());
+
+    Ok(DataRate { write, read, measured: SystemTime::now() })
+}
+
+fn rate(bytes: usize, elapsed: Duration) -> u64 {
+    (bytes as f64 / elapsed.as_secs_f64().max(f64::EPSILON)) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use crate::{hierarchy::FileCarrierHierarchy, rate::{carrier_data_rate, DataRate}};
+
+    #[test]
+    fn measure_and_cache() {
+        let folder = std::env::temp_dir().join(format!("fc-rate-{}", uuid::Uuid::new_v4()));
+        let hierarchy = FileCarrierHierarchy::new(&folder);
+        hierarchy.create_hierarchy().unwrap();
+
+        let measured = carrier_data_rate(&hierarchy).unwrap();
+        let cached: DataRate = fs::read_to_string(hierarchy.rate_file()).unwrap().parse().unwrap();
+        let mode = fs::metadata(hierarchy.rate_file()).unwrap().permissions().mode();
+        let leftovers = fs::read_dir(hierarchy.data()).unwrap().count();
+
+        let _ = fs::remove_dir_all(&folder);
+
+        assert!(measured.bytes_per_second() > 0);
+        assert_eq!(cached.write, measured.write);
+        assert_eq!(cached.read, measured.read);
+        assert_eq!(leftovers, 0);
+        assert_eq!(mode & 0o777, 0o666);
+    }
+}
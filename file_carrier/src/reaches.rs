@@ -0,0 +1,212 @@
+use std::{fmt::Display, str::FromStr, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+const HEADER: &str = "# eid first_seen last_seen visits last_sync dated_visits";
+const UNKNOWN: &str = "-";
+
+/// History of a file carrier at one node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReachRecord {
+    /// EID of the node
+    pub eid: String,
+    /// First time the carrier was registered to the node, if known
+    pub first_seen: Option<SystemTime>,
+    /// Last time the carrier was registered to the node, if known
+    pub last_seen: Option<SystemTime>,
+    /// Number of times the carrier was registered to the node
+    pub visits: u32,
+    /// Number of visits between `first_seen` and `last_seen`, visits imported from the legacy format are not dated
+    pub dated_visits: u32,
+    /// Duration of the last connection of the carrier to the node, if known
+    pub last_sync: Option<Duration>
+}
+
+impl ReachRecord {
+    /// Creates a record of a node without any known visit date
+    pub fn new(eid: String) -> Self {
+        Self {
+            eid,
+            first_seen: None,
+            last_seen: None,
+            visits: 1,
+            dated_visits: 0,
+            last_sync: None
+        }
+    }
+}
+
+/// Nodes reached by a file carrier, stored in the `reaches` file
+///
+/// Records are kept in recency order, most recent first.
+/// Lines containing only an EID (format used before the history was structured) are read
+/// as records without known dates, their position in the file giving their recency.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReachesHistory {
+    records: Vec<ReachRecord>
+}
+
+impl ReachesHistory {
+    pub fn records(&self) -> &[ReachRecord] {
+        &self.records
+    }
+
+    /// Returns the [ReachRecord] of the provided node
+    pub fn get(&self, eid: &str) -> Option<&ReachRecord> {
+        self.records.iter().find(|r| r.eid == eid)
+    }
+
+    /// Returns `true` if no node was ever reached
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Records a new visit of the carrier to the provided node and puts it first
+    pub fn record_visit(&mut self, eid: &str, at: SystemTime) {
+        let mut record = match self.records.iter().position(|r| r.eid == eid) {
+            Some(index) => {
+                let mut record = self.records.remove(index);
+                record.visits = record.visits.saturating_add(1);
+                record
+            },
+            None => ReachRecord::new(eid.to_owned())
+        };
+
+        record.dated_visits = record.dated_visits.saturating_add(1);
+        record.first_seen.get_or_insert(at);
+        record.last_seen = Some(at);
+        self.records.insert(0, record);
+    }
+
+    /// Records the duration of the current connection of the carrier to the provided node
+    pub fn record_sync(&mut self, eid: &str, duration: Duration) {
+        if let Some(record) = self.records.iter_mut().find(|r| r.eid == eid) {
+            record.last_sync = Some(duration);
+        }
+    }
+
+    /// Iterates over records by last visit, most recent first.
+    /// Records without known date come after dated ones, in file order.
+    pub fn by_recency(&self) -> impl Iterator<Item = &ReachRecord> {
+        let mut records: Vec<&ReachRecord> = self.records.iter().collect();
+        records.sort_by(|a, b| match (a.last_seen, b.last_seen) {
+            (Some(a), Some(b)) => b.cmp(&a),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => std::cmp::Ordering::Equal
+        });
+        records.into_iter()
+    }
+}
+
+impl FromStr for ReachesHistory {
+    type Err = String;
+
+    fn from_str(content: &str) -> Result<Self, Self::Err> {
+        let mut records = Vec::new();
+
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let record = match fields[..] {
+                [eid] => ReachRecord::new(eid.to_owned()),
+                [eid, first_seen, last_seen, visits, last_sync, ref dated_visits @ ..] if dated_visits.len() <= 1 => {
+                    let first_seen = parse_optional(first_seen)?.map(|s| UNIX_EPOCH + Duration::from_secs(s));
+                    let visits = visits.parse().map_err(|e| format!("invalid visit count \"{visits}\": {e}"))?;
+                    // Layout 2, written before dated visits were counted, every visit is assumed dated once a date is known
+                    let dated_visits = match dated_visits {
+                        [dated] => dated.parse().map_err(|e| format!("invalid visit count \"{dated}\": {e}"))?,
+                        _ if first_seen.is_some() => visits,
+                        _ => 0
+                    };
+
+                    ReachRecord {
+                        eid: eid.to_owned(),
+                        first_seen,
+                        last_seen: parse_optional(last_seen)?.map(|s| UNIX_EPOCH + Duration::from_secs(s)),
+                        visits,
+                        dated_visits,
+                        last_sync: parse_optional(last_sync)?.map(Duration::from_secs)
+                    }
+                },
+                _ => return Err(format!("malformed line \"{line}\""))
+            };
+
+            if records.iter().all(|r: &ReachRecord| r.eid != record.eid) {
+                records.push(record);
+            }
+        }
+
+        Ok(Self { records })
+    }
+}
+
+impl Display for ReachesHistory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        for record in self.records.iter() {
+            writeln!(f, "{} {} {} {} {} {}",
+                record.eid,
+                format_optional(record.first_seen.map(unix_seconds)),
+                format_optional(record.last_seen.map(unix_seconds)),
+                record.visits,
+                format_optional(record.last_sync.map(|d| d.as_secs())),
+                record.dated_visits
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_optional(value: &str) -> Result<Option<u64>, String> {
+    if value == UNKNOWN {
+        return Ok(None);
+    }
+    value.parse().map(Some).map_err(|e| format!("invalid number \"{value}\": {e}"))
+}
+
+fn format_optional(value: Option<u64>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_else(|| UNKNOWN.to_owned())
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::reaches::ReachesHistory;
+
+    #[test]
+    fn read_legacy_format() {
+        let history: ReachesHistory = "dtn://a/\ndtn://b/\n".parse().unwrap();
+        let eids: Vec<&str> = history.by_recency().map(|r| r.eid.as_str()).collect();
+        assert_eq!(eids, vec!["dtn://a/", "dtn://b/"]);
+        assert!(history.records().iter().all(|r| r.last_seen.is_none() && r.visits == 1));
+    }
+
+    #[test]
+    fn record_visits_roundtrip() {
+        let mut history: ReachesHistory = "dtn://a/\ndtn://b/".parse().unwrap();
+        history.record_visit("dtn://b/", UNIX_EPOCH + Duration::from_secs(1000));
+        history.record_visit("dtn://c/", UNIX_EPOCH + Duration::from_secs(2000));
+        history.record_visit("dtn://b/", UNIX_EPOCH + Duration::from_secs(3000));
+        history.record_sync("dtn://b/", Duration::from_secs(60));
+
+        let parsed: ReachesHistory = history.to_string().parse().unwrap();
+        assert_eq!(parsed, history);
+
+        let eids: Vec<&str> = parsed.by_recency().map(|r| r.eid.as_str()).collect();
+        assert_eq!(eids, vec!["dtn://b/", "dtn://c/", "dtn://a/"]);
+
+        let b = parsed.get("dtn://b/").unwrap();
+        assert_eq!(b.visits, 3);
+        assert_eq!(b.dated_visits, 2);
+        assert_eq!(b.first_seen, Some(UNIX_EPOCH + Duration::from_secs(1000)));
+        assert_eq!(b.last_seen, Some(UNIX_EPOCH + Duration::from_secs(3000)));
+        assert_eq!(b.last_sync, Some(Duration::from_secs(60)));
+    }
+}
@@ -1,7 +1,82 @@
-use std::{path::Path, time::{Duration, SystemTime}, io::BufReader, io::{Write, BufRead}, fs::File};
+use std::{path::{Path, PathBuf}, time::{Duration, SystemTime}, io::Write, fs::File};
 use ud3tn_aap::{config::{Contact, ContactDataRate}, AapStream, BaseAgent, RegisteredAgent};
 
-use crate::{hierarchy::FileCarrierHierarchy, error::FileCarrierError};
+use crate::{hierarchy::FileCarrierHierarchy, error::FileCarrierError, migration::{migrate, MigrationOutcome}, prediction::{predict_contacts, ContactWindow}, rate::carrier_data_rate, strategy::{plan_contacts, ContactStrategy}, unregister::delete_contacts};
+
+/// Maximum number of future contacts predicted from the carrier history
+const PREDICTED_CONTACTS: usize = 4;
+
+/// A contact announced to the node for a carrier
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnouncedContact {
+    /// EID identifying the contact, derived from the carrier
+    pub contact_eid: String,
+    /// EID of the visited node the contact was planned for
+    pub node_eid: String,
+    /// EIDs reachable through the contact
+    pub reaches_eid: Vec<String>,
+    /// Number of nodes visited by the carrier since it left the node
+    pub hops: usize,
+    /// Reliability of the contact, in ud3tn range
+    pub reliability: Option<i32>,
+    /// Time windows during which the carrier is (or is expected to be) connected
+    pub windows: Vec<ContactWindow>
+}
+
+/// Outcome of [register_folder]
+#[derive(Debug, Clone)]
+pub struct RegistrationReport {
+    /// The registered folder
+    pub folder: PathBuf,
+    /// EID of the node the folder was registered to
+    pub node_eid: String,
+    /// Address of the folder for the file CLA
+    pub cla_address: String,
+    /// Contacts announced to the node for the current connection
+    pub contacts: Vec<AnnouncedContact>,
+    /// Contacts announced to the node for the predicted next visits, left to the node at unregistration
+    pub predicted: Vec<AnnouncedContact>,
+    /// Throughput advertised for the contacts in bytes per second, `None` if unlimited
+    pub data_rate: Option<u32>,
+    /// Migration applied to the carrier hierarchy before registration
+    pub migration: MigrationOutcome,
+    /// `true` if no other node ever used this carrier, so nothing could be announced
+    pub first_use: bool,
+    /// Non fatal problems encountered during registration
+    pub warnings: Vec<String>
+}
+
+impl RegistrationReport {
+    /// EIDs of all contacts announced to the node
+    pub fn contact_eids(&self) -> Vec<String> {
+        self.contacts.iter().map(|c| c.contact_eid.clone()).collect()
+    }
+
+    /// EIDs of all nodes reachable through the announced contacts
+    pub fn reaches_eid(&self) -> Vec<String> {
+        let mut reaches: Vec<String> = Vec::new();
+        for eid in self.contacts.iter().flat_map(|c| c.reaches_eid.iter()) {
+            if !reaches.contains(eid) {
+                reaches.push(eid.clone());
+            }
+        }
+        reaches
+    }
+
+    /// Moves the window of every current contact to start at `now`, to announce the contacts again to a restarted node
+    ///
+    /// Predicted windows starting before the end of the current one are dropped.
+    pub fn renew_windows(&mut self, now: SystemTime, duration: Duration) {
+        let current = ContactWindow { start: now, end: now + duration };
+        for contact in self.contacts.iter_mut() {
+            contact.windows = vec![current];
+        }
+        for contact in self.predicted.iter_mut() {
+            contact.windows.retain(|w| w.start >= current.end);
+        }
+        self.predicted.retain(|contact| !contact.windows.is_empty());
+    }
+}
 
 /// Register a folder to a node
 /// # Argument
@@ -9,87 +84,179 @@ use crate::{hierarchy::FileCarrierHierarchy, error::FileCarrierError};
 /// * `aap_agent` - A [&mut Agent] to send bundle through
 /// * `folder` - The folder [&Path] to register
 /// * `duration` - The duration of the connection
+/// * `strategy` - The [ContactStrategy] used to announce nodes reached by the carrier
 /// 
-/// Returns Node Id of contact
-pub fn register_folder<S: AapStream>(aap_agent: &mut RegisteredAgent<S>, folder: &Path, duration: Duration) -> Result<String, FileCarrierError> {
-    let hierarchy = FileCarrierHierarchy::new(&folder);
+/// Returns a [RegistrationReport] describing announced contacts
+pub fn register_folder<S: AapStream>(aap_agent: &mut RegisteredAgent<S>, folder: &Path, duration: Duration, strategy: ContactStrategy) -> Result<RegistrationReport, FileCarrierError> {
+    let hierarchy = FileCarrierHierarchy::new(folder);
     
     if !hierarchy.try_exists()? {
         return Err(FileCarrierError::NotAFileCarrier(folder.to_path_buf()));
     }
 
+    let migration = migrate(folder)?;
+
+    let Some(manifest) = hierarchy.read_manifest()? else {
+        return Err(FileCarrierError::InvalidManifest(hierarchy.manifest_file().to_path_buf(), "missing".to_owned()));
+    };
+
     let current_node = aap_agent.node_id().to_owned();
-    let mut reaches: Vec<String> = Vec::new();
 
-    let file = File::open(hierarchy.reaches_file())?;
-    let reader = BufReader::new(file);
+    let data = hierarchy.data().canonicalize()?;
+    let Some(data_path) = data.to_str() else {
+        return Err(FileCarrierError::NonUtf8Path(data));
+    };
+    let cla_address = format!("file:{data_path}");
 
-    reaches.push(current_node.clone());
-    
-    for line in reader.lines() {
-        let line = line?;
-        if line != current_node {
-            reaches.push(line);
-        }
-    }
+    let now = SystemTime::now();
+    let mut history = hierarchy.reaches()?;
+    history.record_visit(&current_node, now);
 
-    if reaches.len() <= 1 {
-        println!("You're the only one using this file-carrier");
-        println!("Connect to another node to establish a connection");
-        println!("or manually add node EID's in {}", hierarchy.reaches_file().display());
-    }
-    else {
-        let msg = ud3tn_aap::config::ConfigBundle::AddContact {
-            eid: reaches[1].clone(),
-            reliability: None,
-            cla_address: format!("file:{}", hierarchy.data().canonicalize()?.to_str().unwrap()),
-            reaches_eid: reaches[1..reaches.len()].to_vec(),
-            contacts: vec![Contact { start: SystemTime::now(), end: SystemTime::now() + duration, data_rate: ContactDataRate::Unlimited }],
+    let planned = plan_contacts(&history, &current_node, strategy, now);
+
+    let mut report = RegistrationReport {
+        folder: folder.to_path_buf(),
+        node_eid: current_node.clone(),
+        cla_address,
+        contacts: Vec::new(),
+        predicted: Vec::new(),
+        data_rate: None,
+        migration,
+        first_use: planned.is_empty(),
+        warnings: Vec::new()
+    };
+
+    if !planned.is_empty() {
+        // A carrier which can't be measured (full, read-only...) is still announced
+        report.data_rate = match carrier_data_rate(&hierarchy) {
+            Ok(rate) => Some(rate.bytes_per_second().min(u32::MAX as u64) as u32),
+            Err(e) => {
+                report.warnings.push(format!("Failed to measure carrier throughput, announcing unlimited data rate: {e}"));
+                None
+            }
         };
 
-        aap_agent.send_config(msg)?;
+        let predicted_windows = history.get(&current_node)
+            .map(|record| predict_contacts(record, now + duration, PREDICTED_CONTACTS))
+            .unwrap_or_default();
 
-        println!("Connected to node {} for {} seconds", reaches[1], duration.as_secs());
-        println!("Reaches are: {}", reaches[1..].join(";"));
+        let announce_all = || -> Result<(), FileCarrierError> {
+            for (index, contact) in planned.into_iter().enumerate() {
+                let announced = AnnouncedContact {
+                    contact_eid: manifest.contact_eid(index),
+                    node_eid: contact.eid,
+                    reaches_eid: contact.reaches_eid,
+                    hops: contact.hops,
+                    reliability: contact.reliability,
+                    windows: vec![ContactWindow { start: now, end: now + duration }]
+                };
 
+                announce_contact(aap_agent, &report.cla_address, &announced, report.data_rate)?;
+                report.contacts.push(announced.clone());
+
+                if !predicted_windows.is_empty() {
+                    let predicted = AnnouncedContact {
+                        contact_eid: manifest.predicted_contact_eid(index),
+                        windows: predicted_windows.clone(),
+                        ..announced
+                    };
+
+                    // Replaces the predictions announced at the previous visit
+                    delete_contacts(aap_agent, std::slice::from_ref(&predicted.contact_eid))?;
+                    announce_contact(aap_agent, &report.cla_address, &predicted, report.data_rate)?;
+                    report.predicted.push(predicted);
+                }
+            }
+            Ok(())
+        };
+        let announced = announce_all();
+
+        // Contacts sent before a failure are listed too, so unregistration deletes them
         let mut connected_file = File::create(hierarchy.connected_file())?;
-        connected_file.write_all(reaches[1].as_bytes())?;
+        connected_file.write_all(report.contact_eids().join("\n").as_bytes())?;
+        announced?;
     }
     
-    let mut reaches_file = File::create(hierarchy.reaches_file())?;
-    
-    let reaches_concat = reaches.join("\n");
-    
-    reaches_file.write_all(reaches_concat.as_bytes())?;
+    hierarchy.write_reaches(&history)?;
     
-    match reaches.get(1) {
-        Some(eid) => Ok(eid.clone()),
-        None => Err(FileCarrierError::FirstUser(folder.to_owned())),
-    }
+    Ok(report)
+}
+
+/// Sends the `AddContact` configuration of an [AnnouncedContact] to the node
+/// # Argument
+///
+/// * `aap_agent` - A [&mut Agent] to send bundle through
+/// * `cla_address` - Address of the carrier folder for the file CLA
+/// * `contact` - The [AnnouncedContact] to send
+/// * `data_rate` - Throughput of the contact in bytes per second, `None` if unlimited
+pub fn announce_contact<S: AapStream>(aap_agent: &mut RegisteredAgent<S>, cla_address: &str, contact: &AnnouncedContact, data_rate: Option<u32>) -> Result<(), FileCarrierError> {
+    let msg = ud3tn_aap::config::ConfigBundle::AddContact {
+        eid: contact.contact_eid.clone(),
+        reliability: contact.reliability,
+        cla_address: cla_address.to_owned(),
+        reaches_eid: contact.reaches_eid.clone(),
+        contacts: contact.windows.iter()
+            .map(|w| Contact {
+                start: w.start,
+                end: w.end,
+                data_rate: match data_rate {
+                    Some(rate) => ContactDataRate::Limited(rate),
+                    None => ContactDataRate::Unlimited
+                }
+            })
+            .collect(),
+    };
+
+    aap_agent.send_config(msg)?;
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{env, fs, path::Path, time::Duration};
-    use crate::{init::initialize_file_carrier, hierarchy::FileCarrierHierarchy, register::register_folder};
+    use std::{fs, time::Duration};
+    use crate::{hierarchy::FileCarrierHierarchy, init::initialize_file_carrier, register::register_folder, strategy::ContactStrategy, testing::{FakeAapServer, RecordedConfig, TempFolder}};
 
     #[test]
-    fn initialize_fc() {
-        let current_dir = env::current_dir().unwrap();
-        let fc = FileCarrierHierarchy::new(&current_dir);
-        let _ = initialize_file_carrier(&current_dir);
-
-        let mut agent = ud3tn_aap::Agent::connect_unix(
-            &Path::new("/run/archipel-core/archipel-core.socket"),
-        )
-            .expect("u3dtn agent connection failure")
-        .register("file-carrier/105b4168-a93e-459b-8672-09509db759cc".to_owned())
-            .expect("Failed to register agent");
-
-        let res = register_folder(&mut agent, &current_dir, Duration::from_secs(300));
-        
-        let _ = fs::remove_dir_all(fc.root());
-
-        res.expect("Failed at registration");
+    fn register_announces_carrier_contact() {
+        let server = FakeAapServer::start("dtn://current/").unwrap();
+        let folder = TempFolder::new("fc-register").unwrap();
+        let fc = FileCarrierHierarchy::new(folder.path());
+        initialize_file_carrier(folder.path()).unwrap();
+        fs::write(fc.reaches_file(), "dtn://previous/\ndtn://older/").unwrap();
+
+        let mut agent = server.agent("file-carrier/test");
+        let report = register_folder(&mut agent, folder.path(), Duration::from_secs(300), ContactStrategy::LastNode)
+            .expect("Failed at registration");
+
+        let manifest = fc.read_manifest().unwrap().unwrap();
+        assert!(!report.first_use);
+        assert_eq!(report.contact_eids(), vec![manifest.contact_eid(0)]);
+        assert_eq!(fs::read_to_string(fc.connected_file()).unwrap(), manifest.contact_eid(0));
+
+        let configs = server.configs();
+        assert_eq!(configs.len(), 1);
+        let RecordedConfig::AddContact { eid, cla_address, reaches_eid, contacts, .. } = &configs[0] else {
+            panic!("Expected AddContact, got {:?}", configs[0]);
+        };
+        assert_eq!(eid, &manifest.contact_eid(0));
+        assert_eq!(cla_address, &format!("file:{}", fc.data().canonicalize().unwrap().display()));
+        assert_eq!(reaches_eid, &vec!["dtn://previous/".to_owned(), "dtn://older/".to_owned()]);
+        assert_eq!(contacts.len(), 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn register_first_use_sends_nothing() {
+        let server = FakeAapServer::start("dtn://current/").unwrap();
+        let folder = TempFolder::new("fc-register").unwrap();
+        let fc = FileCarrierHierarchy::new(folder.path());
+        initialize_file_carrier(folder.path()).unwrap();
+
+        let mut agent = server.agent("file-carrier/test");
+        let report = register_folder(&mut agent, folder.path(), Duration::from_secs(300), ContactStrategy::LastNode)
+            .expect("Failed at registration");
+
+        assert!(report.first_use);
+        assert!(server.configs().is_empty());
+        assert_eq!(fc.reaches().unwrap().get("dtn://current/").unwrap().visits, 1);
+    }
+}
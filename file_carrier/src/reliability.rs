@@ -0,0 +1,109 @@
+use std::time::{Duration, SystemTime};
+
+use crate::reaches::ReachesHistory;
+
+/// Lowest reliability accepted by ud3tn for a contact
+pub const MIN_RELIABILITY: i32 = 100;
+
+/// Highest reliability accepted by ud3tn for a contact
+pub const MAX_RELIABILITY: i32 = 1000;
+
+/// Age after which a visit of the carrier to a node weights half as much
+pub const HALF_LIFE: Duration = Duration::from_secs(30 * 24 * 3600);
+
+/// Weight of a visit made at an unknown date
+pub const UNKNOWN_DATE_WEIGHT: f64 = 0.5;
+
+/// Factor applied to the reliability of a node for each other node visited since the carrier left it
+pub const HOP_DECAY: f64 = 0.9;
+
+/// Estimates the probability that the carrier, after leaving node `from`, reaches node `to`
+///
+/// A carrier visiting `to` as often as `from` is expected to go from one to the other on each trip,
+/// so the estimation is the ratio of visits of both nodes, lowered as the last visit of `to` gets older.
+/// Returns `None` if `to` was never reached.
+/// # Arguments
+///
+/// * `history` - The [ReachesHistory] of the carrier
+/// * `from` - EID of the node the carrier is leaving
+/// * `to` - EID of the node to estimate reliability for
+/// * `now` - Current time, used to age visits
+pub fn estimate_reliability(history: &ReachesHistory, from: &str, to: &str, now: SystemTime) -> Option<f64> {
+    let to = history.get(to)?;
+    let from_visits = history.get(from).map(|r| r.visits).unwrap_or(1).max(1);
+
+    let ratio = (to.visits as f64 / from_visits as f64).min(1.0);
+
+    let recency = match to.last_seen {
+        Some(last_seen) => {
+            let age = now.duration_since(last_seen).unwrap_or_default();
+            0.5f64.powf(age.as_secs_f64() / HALF_LIFE.as_secs_f64())
+        },
+        None => UNKNOWN_DATE_WEIGHT
+    };
+
+    Some(ratio * recency)
+}
+
+/// Lowers a reliability estimation by [HOP_DECAY] for each node visited after the estimated one
+///
+/// `hops` is `1` for the last visited node, which is not lowered.
+pub fn with_hops(probability: f64, hops: usize) -> f64 {
+    probability * HOP_DECAY.powi(hops.saturating_sub(1).min(i32::MAX as usize) as i32)
+}
+
+/// Converts a probability in `[0, 1]` to a ud3tn contact reliability in
+/// `[MIN_RELIABILITY, MAX_RELIABILITY]`
+pub fn to_contact_reliability(probability: f64) -> i32 {
+    let range = (MAX_RELIABILITY - MIN_RELIABILITY) as f64;
+    MIN_RELIABILITY + (probability.clamp(0.0, 1.0) * range).round() as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::{reaches::ReachesHistory, reliability::{estimate_reliability, to_contact_reliability, with_hops, HALF_LIFE, HOP_DECAY, MAX_RELIABILITY, MIN_RELIABILITY}};
+
+    #[test]
+    fn frequent_and_recent_is_reliable() {
+        let mut history = ReachesHistory::default();
+        let now = UNIX_EPOCH + HALF_LIFE * 10;
+        for i in 0..4 {
+            history.record_visit("dtn://b/", now - Duration::from_secs(7200 - i));
+            history.record_visit("dtn://a/", now - Duration::from_secs(3600 - i));
+        }
+
+        let reliability = estimate_reliability(&history, "dtn://a/", "dtn://b/", now).unwrap();
+        assert!(reliability > 0.99);
+    }
+
+    #[test]
+    fn rare_and_old_is_unreliable() {
+        let mut history = ReachesHistory::default();
+        let now = UNIX_EPOCH + HALF_LIFE * 10;
+        history.record_visit("dtn://b/", now - HALF_LIFE);
+        for _ in 0..4 {
+            history.record_visit("dtn://a/", now);
+        }
+
+        let reliability = estimate_reliability(&history, "dtn://a/", "dtn://b/", now).unwrap();
+        assert!((reliability - 0.125).abs() < 1e-9);
+        assert_eq!(estimate_reliability(&history, "dtn://a/", "dtn://c/", now), None);
+    }
+
+    #[test]
+    fn farther_nodes_are_less_reliable() {
+        assert_eq!(with_hops(0.8, 1), 0.8);
+        assert!((with_hops(0.8, 3) - 0.8 * HOP_DECAY * HOP_DECAY).abs() < 1e-9);
+        assert_eq!(with_hops(0.8, 0), 0.8);
+    }
+
+    #[test]
+    fn contact_reliability_bounds() {
+        assert_eq!(to_contact_reliability(0.0), MIN_RELIABILITY);
+        assert_eq!(to_contact_reliability(1.0), MAX_RELIABILITY);
+        assert_eq!(to_contact_reliability(2.0), MAX_RELIABILITY);
+        assert_eq!(to_contact_reliability(0.5), 550);
+    }
+}
@@ -0,0 +1,156 @@
+use std::{fmt::Display, str::FromStr, time::SystemTime};
+
+use crate::{reaches::ReachesHistory, reliability::{estimate_reliability, to_contact_reliability, with_hops}};
+
+/// How the nodes reached by a carrier are announced to the node it is registered to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContactStrategy {
+    /// A single contact to the last visited node, reaching every other visited node
+    #[default]
+    LastNode,
+    /// One contact per visited node
+    AllNodes,
+    /// One contact per visited node, only for the provided number of most visited nodes
+    TopFrequent(usize)
+}
+
+impl FromStr for ContactStrategy {
+    type Err = String;
+
+    /// Parses `last-node`, `all-nodes` or `top-<N>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "last-node" => Ok(Self::LastNode),
+            "all-nodes" => Ok(Self::AllNodes),
+            _ => match s.strip_prefix("top-").map(str::parse::<usize>) {
+                Some(Ok(n)) if n > 0 => Ok(Self::TopFrequent(n)),
+                _ => Err(format!("invalid strategy \"{s}\", expected last-node, all-nodes or top-<N>"))
+            }
+        }
+    }
+}
+
+impl Display for ContactStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::LastNode => write!(f, "last-node"),
+            Self::AllNodes => write!(f, "all-nodes"),
+            Self::TopFrequent(n) => write!(f, "top-{n}")
+        }
+    }
+}
+
+/// A contact to announce for a carrier
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedContact {
+    /// EID of the contact
+    pub eid: String,
+    /// EIDs reachable through the contact
+    pub reaches_eid: Vec<String>,
+    /// Number of nodes visited by the carrier since it left the node, `1` for the last visited node
+    pub hops: usize,
+    /// Estimated reliability of the contact in ud3tn range, lowered with recency and hops
+    pub reliability: Option<i32>
+}
+
+/// Derives the contacts to announce from the history of a carrier
+/// # Arguments
+///
+/// * `history` - The [ReachesHistory] of the carrier, including the current visit
+/// * `current_node` - EID of the node the carrier is registered to
+/// * `strategy` - The [ContactStrategy] to apply
+/// * `now` - Current time, used to estimate reliability
+pub fn plan_contacts(history: &ReachesHistory, current_node: &str, strategy: ContactStrategy, now: SystemTime) -> Vec<PlannedContact> {
+    let others: Vec<(usize, &str, u32)> = history.by_recency()
+        .filter(|record| record.eid != current_node)
+        .enumerate()
+        .map(|(index, record)| (index + 1, record.eid.as_str(), record.visits))
+        .collect();
+
+    let reliability = |eid: &str, hops: usize| estimate_reliability(history, current_node, eid, now)
+        .map(|probability| to_contact_reliability(with_hops(probability, hops)));
+
+    let single = |(hops, eid, _): &(usize, &str, u32)| PlannedContact {
+        eid: eid.to_string(),
+        reaches_eid: vec![eid.to_string()],
+        hops: *hops,
+        reliability: reliability(eid, *hops)
+    };
+
+    match strategy {
+        ContactStrategy::LastNode => others.first()
+            .map(|(hops, eid, _)| PlannedContact {
+                eid: eid.to_string(),
+                reaches_eid: others.iter().map(|(_, eid, _)| eid.to_string()).collect(),
+                hops: *hops,
+                reliability: reliability(eid, *hops)
+            })
+            .into_iter()
+            .collect(),
+        ContactStrategy::AllNodes => others.iter().map(single).collect(),
+        ContactStrategy::TopFrequent(n) => {
+            let mut frequent = others.clone();
+            // Closest nodes first among equally visited ones
+            frequent.sort_by_key(|(hops, _, visits)| (std::cmp::Reverse(*visits), *hops));
+            frequent.iter().take(n).map(single).collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::{reaches::ReachesHistory, reliability::{estimate_reliability, to_contact_reliability, HOP_DECAY}, strategy::{plan_contacts, ContactStrategy}};
+
+    fn history() -> ReachesHistory {
+        let mut history = ReachesHistory::default();
+        let visits = ["dtn://b/", "dtn://c/", "dtn://b/", "dtn://d/", "dtn://a/"];
+        for (i, eid) in visits.iter().enumerate() {
+            history.record_visit(eid, UNIX_EPOCH + Duration::from_secs(i as u64 * 3600));
+        }
+        history
+    }
+
+    fn eids(strategy: ContactStrategy) -> Vec<(String, usize)> {
+        plan_contacts(&history(), "dtn://a/", strategy, UNIX_EPOCH + Duration::from_secs(5 * 3600))
+            .into_iter()
+            .map(|c| (c.eid, c.hops))
+            .collect()
+    }
+
+    #[test]
+    fn last_node() {
+        let contacts = plan_contacts(&history(), "dtn://a/", ContactStrategy::LastNode, UNIX_EPOCH);
+        assert_eq!(contacts.len(), 1);
+        assert_eq!(contacts[0].eid, "dtn://d/");
+        assert_eq!(contacts[0].reaches_eid, vec!["dtn://d/", "dtn://b/", "dtn://c/"]);
+    }
+
+    #[test]
+    fn all_nodes_and_top() {
+        assert_eq!(eids(ContactStrategy::AllNodes), vec![
+            ("dtn://d/".to_owned(), 1), ("dtn://b/".to_owned(), 2), ("dtn://c/".to_owned(), 3)
+        ]);
+        assert_eq!(eids(ContactStrategy::TopFrequent(1)), vec![("dtn://b/".to_owned(), 2)]);
+    }
+
+    #[test]
+    fn hops_lower_reliability() {
+        // c is the third node visited since the carrier left it
+        let now = UNIX_EPOCH + Duration::from_secs(5 * 3600);
+        let contacts = plan_contacts(&history(), "dtn://a/", ContactStrategy::AllNodes, now);
+        let reliability = |eid: &str| contacts.iter().find(|c| c.eid == eid).unwrap().reliability.unwrap();
+        let expected = estimate_reliability(&history(), "dtn://a/", "dtn://c/", now).unwrap();
+        assert_eq!(reliability("dtn://c/"), to_contact_reliability(expected * HOP_DECAY * HOP_DECAY));
+        assert!(reliability("dtn://d/") > reliability("dtn://c/"));
+    }
+
+    #[test]
+    fn parse_strategy() {
+        for strategy in [ContactStrategy::LastNode, ContactStrategy::AllNodes, ContactStrategy::TopFrequent(3)] {
+            assert_eq!(strategy.to_string().parse::<ContactStrategy>(), Ok(strategy));
+        }
+        assert!("top-0".parse::<ContactStrategy>().is_err());
+    }
+}
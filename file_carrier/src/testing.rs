@@ -0,0 +1,282 @@
+//! Test support : an in-memory stand-in for Archipel Core speaking AAP over a unix socket,
+//! recording every bundle sent by agents so tests can assert on configuration sent to the core.
+
+use std::{fs, io::{self, Read, Write}, os::unix::net::{UnixListener, UnixStream}, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, Mutex}, thread};
+
+use ud3tn_aap::{Agent, RegisteredAgent};
+use uuid::Uuid;
+
+const AAP_VERSION: u8 = 0x1;
+const AAP_ACK: u8 = 0x0;
+const AAP_REGISTER: u8 = 0x2;
+const AAP_SENDBUNDLE: u8 = 0x3;
+const AAP_SENDCONFIRM: u8 = 0x5;
+const AAP_WELCOME: u8 = 0x7;
+const AAP_PING: u8 = 0x8;
+
+/// A bundle sent by an agent to the [FakeAapServer]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedBundle {
+    /// Identifier the sending agent registered with
+    pub agent_id: Option<String>,
+    /// Destination EID of the bundle
+    pub destination: String,
+    /// Payload of the bundle
+    pub payload: Vec<u8>
+}
+
+/// A configuration bundle received by the [FakeAapServer], parsed from the ud3tn configuration format
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordedConfig {
+    AddContact {
+        eid: String,
+        reliability: Option<i32>,
+        cla_address: String,
+        reaches_eid: Vec<String>,
+        /// Raw `{start,end,rate}` content of each contact
+        contacts: Vec<String>
+    },
+    DeleteContact(String),
+    /// Configuration which could not be parsed
+    Other(String)
+}
+
+impl RecordedConfig {
+    /// Parses a ud3tn configuration command such as `1(dtn://a/),500:(file:/x):[(dtn://b/)]:[{1,2,3}];`
+    pub fn parse(command: &str) -> Self {
+        Self::try_parse(command).unwrap_or_else(|| Self::Other(command.to_owned()))
+    }
+
+    fn try_parse(command: &str) -> Option<Self> {
+        let command = command.trim().trim_end_matches(';');
+        let (kind, rest) = command.split_at_checked(1)?;
+        let (eid, mut rest) = rest.strip_prefix('(')?.split_once(')')?;
+
+        match kind {
+            "1" | "2" => {
+                let mut reliability = None;
+                if let Some(r) = rest.strip_prefix(',') {
+                    let (value, r) = r.split_once(':')?;
+                    reliability = Some(value.parse().ok()?);
+                    rest = r;
+                } else {
+                    rest = rest.strip_prefix(':')?;
+                }
+
+                let (cla_address, mut rest) = rest.strip_prefix('(')?.split_once(')')?;
+
+                let mut reaches_eid = Vec::new();
+                if let Some(r) = rest.strip_prefix(":[") {
+                    let (list, r) = r.split_once(']')?;
+                    reaches_eid = list.split(',')
+                        .map(|e| e.trim_start_matches('(').trim_end_matches(')').to_owned())
+                        .filter(|e| !e.is_empty())
+                        .collect();
+                    rest = r;
+                } else if let Some(r) = rest.strip_prefix(':') {
+                    rest = r;
+                }
+
+                let mut contacts = Vec::new();
+                if let Some(r) = rest.strip_prefix(":[").or(rest.strip_prefix('[')) {
+                    let (list, _) = r.split_once(']')?;
+                    contacts = list.split('}')
+                        .map(|c| c.trim_start_matches(',').trim_start_matches('{').to_owned())
+                        .filter(|c| !c.is_empty())
+                        .collect();
+                }
+
+                Some(Self::AddContact {
+                    eid: eid.to_owned(),
+                    reliability,
+                    cla_address: cla_address.to_owned(),
+                    reaches_eid,
+                    contacts
+                })
+            },
+            "3" => Some(Self::DeleteContact(eid.to_owned())),
+            _ => None
+        }
+    }
+}
+
+/// A fake Archipel Core listening on a unix socket in the temporary directory
+///
+/// It greets agents with the configured node EID, acknowledges registrations and pings
+/// and confirms every bundle, recording them.
+pub struct FakeAapServer {
+    socket_path: PathBuf,
+    node_eid: String,
+    bundles: Arc<Mutex<Vec<RecordedBundle>>>,
+    connections: Arc<Mutex<Vec<UnixStream>>>,
+    stopped: Arc<AtomicBool>
+}
+
+impl FakeAapServer {
+    /// Starts a server announcing itself as `node_eid`
+    pub fn start(node_eid: &str) -> io::Result<Self> {
+        let socket_path = std::env::temp_dir().join(format!("fc-aap-{}.socket", Uuid::new_v4()));
+        let listener = UnixListener::bind(&socket_path)?;
+
+        let server = Self {
+            socket_path,
+            node_eid: node_eid.to_owned(),
+            bundles: Arc::default(),
+            connections: Arc::default(),
+            stopped: Arc::default()
+        };
+
+        let node_eid = server.node_eid.clone();
+        let bundles = server.bundles.clone();
+        let connections = server.connections.clone();
+        let stopped = server.stopped.clone();
+        let bundle_ids = Arc::new(AtomicU64::new(1));
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopped.load(Ordering::SeqCst) {
+                    return;
+                }
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                if let Ok(clone) = stream.try_clone() {
+                    connections.lock().unwrap().push(clone);
+                }
+
+                let node_eid = node_eid.clone();
+                let bundles = bundles.clone();
+                let bundle_ids = bundle_ids.clone();
+                thread::spawn(move || {
+                    let _ = serve(stream, &node_eid, &bundles, &bundle_ids);
+                });
+            }
+        });
+
+        Ok(server)
+    }
+
+    pub fn socket_path(&self) -> &Path {
+        &self.socket_path
+    }
+
+    pub fn node_eid(&self) -> &str {
+        &self.node_eid
+    }
+
+    /// Connects and registers a new agent to this server
+    pub fn agent(&self, agent_id: &str) -> RegisteredAgent<UnixStream> {
+        Agent::connect_unix(&self.socket_path)
+            .expect("Failed to connect to fake AAP server")
+            .register(agent_id.to_owned())
+            .expect("Failed to register to fake AAP server")
+    }
+
+    /// Every bundle received so far
+    pub fn bundles(&self) -> Vec<RecordedBundle> {
+        self.bundles.lock().unwrap().clone()
+    }
+
+    /// Every configuration bundle received so far
+    pub fn configs(&self) -> Vec<RecordedConfig> {
+        self.bundles().into_iter()
+            .filter(|b| b.destination.ends_with("/config"))
+            .map(|b| RecordedConfig::parse(&String::from_utf8_lossy(&b.payload)))
+            .collect()
+    }
+
+    /// Forgets every bundle received so far
+    pub fn clear(&self) {
+        self.bundles.lock().unwrap().clear();
+    }
+
+    /// Closes every open agent connection, as a restarting core would
+    pub fn disconnect_all(&self) {
+        for stream in self.connections.lock().unwrap().drain(..) {
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        }
+    }
+}
+
+impl Drop for FakeAapServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.disconnect_all();
+        // Wakes the listener up so it notices it is stopped
+        let _ = UnixStream::connect(&self.socket_path);
+        let _ = fs::remove_file(&self.socket_path);
+    }
+}
+
+fn serve(mut stream: UnixStream, node_eid: &str, bundles: &Mutex<Vec<RecordedBundle>>, bundle_ids: &AtomicU64) -> io::Result<()> {
+    let mut welcome = vec![header(AAP_WELCOME)];
+    write_string(&mut welcome, node_eid);
+    stream.write_all(&welcome)?;
+
+    let mut agent_id = None;
+
+    loop {
+        let mut head = [0u8; 1];
+        stream.read_exact(&mut head)?;
+
+        match head[0] & 0x0F {
+            AAP_REGISTER => {
+                agent_id = Some(read_string(&mut stream)?);
+                stream.write_all(&[header(AAP_ACK)])?;
+            },
+            AAP_SENDBUNDLE => {
+                let destination = read_string(&mut stream)?;
+                let mut length = [0u8; 8];
+                stream.read_exact(&mut length)?;
+                let mut payload = vec![0u8; u64::from_be_bytes(length) as usize];
+                stream.read_exact(&mut payload)?;
+
+                bundles.lock().unwrap().push(RecordedBundle { agent_id: agent_id.clone(), destination, payload });
+
+                let mut confirm = vec![header(AAP_SENDCONFIRM)];
+                confirm.extend_from_slice(&bundle_ids.fetch_add(1, Ordering::SeqCst).to_be_bytes());
+                stream.write_all(&confirm)?;
+            },
+            AAP_PING => stream.write_all(&[header(AAP_ACK)])?,
+            other => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported AAP message {other}")))
+        }
+    }
+}
+
+fn header(message_type: u8) -> u8 {
+    (AAP_VERSION << 4) | message_type
+}
+
+fn read_string(stream: &mut UnixStream) -> io::Result<String> {
+    let mut length = [0u8; 2];
+    stream.read_exact(&mut length)?;
+    let mut value = vec![0u8; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut value)?;
+    String::from_utf8(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_string(buffer: &mut Vec<u8>, value: &str) {
+    buffer.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(value.as_bytes());
+}
+
+/// A folder in the temporary directory, removed when dropped
+pub struct TempFolder(PathBuf);
+
+impl TempFolder {
+    pub fn new(prefix: &str) -> io::Result<Self> {
+        let path = std::env::temp_dir().join(format!("{}-{}", prefix, Uuid::new_v4()));
+        fs::create_dir_all(&path)?;
+        Ok(Self(path))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempFolder {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
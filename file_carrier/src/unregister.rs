@@ -1,30 +1,123 @@
-use std::{path::Path, io::Read, fs::{File, self}};
-use ud3tn_aap::{AapStream, RegisteredAgent};
+use std::{path::{Path, PathBuf}, io::Read, fs::{File, self}, time::{Duration, SystemTime}};
+use ud3tn_aap::{AapStream, BaseAgent, RegisteredAgent};
 
 use crate::{hierarchy::FileCarrierHierarchy, error::FileCarrierError};
 
+/// Outcome of [unregister_folder]
+#[derive(Debug, Clone)]
+pub struct UnregistrationReport {
+    /// The unregistered folder
+    pub folder: PathBuf,
+    /// EIDs of the contacts deleted from the node
+    pub contact_eids: Vec<String>,
+    /// Duration of the connection of the carrier to the node, if known
+    pub connected_for: Option<Duration>
+}
+
 /// Unregister a folder from a node
 /// # Argument
 ///
 /// * `aap_agent` - A [&mut Agent] to send bundle through
 /// * `folder` - The folder [&Path] to unregister
-pub fn unregister_folder<S:AapStream>(aap_agent: &mut RegisteredAgent<S>, folder: &Path) -> Result<(), FileCarrierError> {
-    let hierarchy = FileCarrierHierarchy::new(&folder);
+pub fn unregister_folder<S:AapStream>(aap_agent: &mut RegisteredAgent<S>, folder: &Path) -> Result<UnregistrationReport, FileCarrierError> {
+    let hierarchy = FileCarrierHierarchy::new(folder);
 
     if !hierarchy.try_exists()? {
-        println!("Folder {} is not a file-carrier", folder.display());
         return Err(FileCarrierError::NotAFileCarrier(folder.to_path_buf()));
     }
 
+    hierarchy.read_manifest()?;
+
     let mut connected_eid = String::new();
     File::open(hierarchy.connected_file())?.read_to_string(&mut connected_eid)?;
     
-    let msg = ud3tn_aap::config::ConfigBundle::DeleteContact(connected_eid);
-    aap_agent.send_config(msg)?;
+    let contact_eids: Vec<String> = connected_eid.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(str::to_owned)
+        .collect();
+
+    delete_contacts(aap_agent, &contact_eids)?;
 
     fs::remove_file(hierarchy.connected_file())?;
-    
-    println!("Unregistered {}", folder.display());
 
+    let current_node = aap_agent.node_id().to_owned();
+    let mut history = hierarchy.reaches()?;
+    let connected_since = history.get(&current_node).and_then(|r| r.last_seen);
+    let connected_for = connected_since.and_then(|since| SystemTime::now().duration_since(since).ok());
+    if let Some(duration) = connected_for {
+        history.record_sync(&current_node, duration);
+        hierarchy.write_reaches(&history)?;
+    }
+
+    Ok(UnregistrationReport {
+        folder: folder.to_path_buf(),
+        contact_eids,
+        connected_for
+    })
+}
+
+/// Deletes contacts from a node without touching the carrier, for carriers which are no longer reachable
+/// # Argument
+///
+/// * `aap_agent` - A [&mut Agent] to send bundle through
+/// * `contact_eids` - EIDs of the contacts to delete
+pub fn delete_contacts<S:AapStream>(aap_agent: &mut RegisteredAgent<S>, contact_eids: &[String]) -> Result<(), FileCarrierError> {
+    for eid in contact_eids.iter() {
+        let msg = ud3tn_aap::config::ConfigBundle::DeleteContact(eid.clone());
+        aap_agent.send_config(msg)?;
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::{Duration, SystemTime, UNIX_EPOCH}};
+    use crate::{hierarchy::FileCarrierHierarchy, init::initialize_file_carrier, register::register_folder, strategy::ContactStrategy, testing::{FakeAapServer, RecordedConfig, TempFolder}, unregister::unregister_folder};
+
+    #[test]
+    fn unregister_deletes_announced_contacts() {
+        let server = FakeAapServer::start("dtn://current/").unwrap();
+        let folder = TempFolder::new("fc-unregister").unwrap();
+        let fc = FileCarrierHierarchy::new(folder.path());
+        initialize_file_carrier(folder.path()).unwrap();
+        fs::write(fc.reaches_file(), "dtn://a/\ndtn://b/").unwrap();
+
+        let mut agent = server.agent("file-carrier/test");
+        let registration = register_folder(&mut agent, folder.path(), Duration::from_secs(300), ContactStrategy::AllNodes).unwrap();
+        server.clear();
+
+        let report = unregister_folder(&mut agent, folder.path()).unwrap();
+
+        assert_eq!(report.contact_eids, registration.contact_eids());
+        assert_eq!(server.configs(), registration.contact_eids().into_iter()
+            .map(RecordedConfig::DeleteContact)
+            .collect::<Vec<_>>());
+        assert!(!fc.connected_file().try_exists().unwrap());
+        assert!(fc.reaches().unwrap().get("dtn://current/").unwrap().last_sync.is_some());
+    }
+
+    #[test]
+    fn unregister_keeps_predicted_contacts() {
+        const WEEK: u64 = 7 * 24 * 3600;
+
+        let server = FakeAapServer::start("dtn://current/").unwrap();
+        let folder = TempFolder::new("fc-unregister").unwrap();
+        let fc = FileCarrierHierarchy::new(folder.path());
+        initialize_file_carrier(folder.path()).unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        fs::write(fc.reaches_file(), format!("dtn://current/ {} {} 3 1800 3\ndtn://previous/ - - 1 - 0", now - 3 * WEEK, now - WEEK)).unwrap();
+
+        let mut agent = server.agent("file-carrier/test");
+        let registration = register_folder(&mut agent, folder.path(), Duration::from_secs(300), ContactStrategy::LastNode).unwrap();
+        let predicted_eid = registration.predicted[0].contact_eid.clone();
+        assert!(server.configs().iter().any(|c| matches!(c, RecordedConfig::AddContact { eid, contacts, .. } if eid == &predicted_eid && !contacts.is_empty())));
+        server.clear();
+
+        unregister_folder(&mut agent, folder.path()).unwrap();
+
+        assert!(!registration.contact_eids().contains(&predicted_eid));
+        assert!(!server.configs().contains(&RecordedConfig::DeleteContact(predicted_eid)));
+        assert_eq!(server.configs().len(), registration.contacts.len());
+    }
+}